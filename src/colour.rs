@@ -0,0 +1,363 @@
+//! Colour schemes and palettes for arcs
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use hsluv::hsluv_to_hex;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::gtfs::{gc_distance, Quantity, StopId};
+
+/// Okabe & Ito's colourblind-safe categorical palette
+const OKABE_ITO: [&str; 8] =
+    ["#E69F00", "#56B4E9", "#009E73", "#F0E442", "#0072B2", "#D55E00", "#CC79A7", "#000000"];
+
+/// Anchor points for the sequential ramp (sampled from viridis)
+const VIRIDIS: [(u8, u8, u8); 5] = [
+    (0x44, 0x01, 0x54),
+    (0x3b, 0x52, 0x8b),
+    (0x21, 0x91, 0x8c),
+    (0x5e, 0xc9, 0x62),
+    (0xfd, 0xe7, 0x25),
+];
+
+/// The available ways of picking one colour per stop
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scheme {
+    /// Evenly spaced `HSLuv` hues along the route, so that neighbouring stops look alike
+    Hues,
+    /// A colourblind-safe categorical palette, repeated as necessary
+    Categorical,
+    /// A ramp from dark to light by position along the route
+    Sequential,
+    /// Shades of grey, for print
+    Mono,
+}
+
+/// What each arc is coloured by
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColourBy {
+    /// The stop where passengers boarded
+    Origin,
+    /// The stop where passengers alighted
+    Destination,
+    /// Trip length, counted in stops
+    Stops,
+    /// Trip length, as great-circle distance between origin and destination
+    Distance,
+    /// The most common `ticket_type` for each origin-destination pair
+    Ticket,
+    /// The most common `time` bucket for each origin-destination pair
+    Time,
+    /// The route carrying the most passengers for each origin-destination pair (for corridors)
+    Route,
+}
+
+impl ColourBy {
+    /// A short description, for legends
+    pub const fn describe(self) -> &'static str {
+        match self {
+            Self::Origin => "origin stop",
+            Self::Destination => "destination stop",
+            Self::Stops => "trip length (stops)",
+            Self::Distance => "trip length (straight-line distance)",
+            Self::Ticket => "most common ticket type",
+            Self::Time => "most common time of day",
+            Self::Route => "route carrying the most passengers",
+        }
+    }
+}
+
+/// Colour classes for arcs, when they aren't simply coloured by stop
+#[derive(Debug, Clone, Default)]
+pub struct ArcClasses {
+    /// {(origin, destination) : class index}
+    pub classes: BTreeMap<(StopId, StopId), usize>,
+    /// Legend text for each class
+    pub labels: Vec<String>,
+    /// Colour for each class
+    pub colours: Vec<String>,
+}
+
+/// Everything needed to decide on the colour of each stop
+#[derive(Debug, Clone)]
+pub struct Palette {
+    /// Which colour scheme to use (ignored if `custom` is set)
+    pub scheme: Scheme,
+    /// First hue, in degrees (for [`Scheme::Hues`])
+    pub start: f64,
+    /// Last hue, in degrees (for [`Scheme::Hues`])
+    pub stop: f64,
+    /// `HSLuv` saturation, 0 - 100 (for [`Scheme::Hues`])
+    pub chroma: f64,
+    /// `HSLuv` lightness, 0 - 100 (for [`Scheme::Hues`] and [`Scheme::Mono`])
+    pub lightness: f64,
+    /// Seed for the variance in saturation (for [`Scheme::Hues`])
+    pub seed: u64,
+    /// Colour neighbours differently rather than similarly
+    pub jumble: bool,
+    /// A user-supplied list of colours, used in preference to `scheme`
+    pub custom: Option<Vec<String>>,
+}
+
+impl Palette {
+    /// Colours for each stop in `stops`, in the same order
+    pub fn stop_colours(&self, stops: &[StopId]) -> Vec<String> {
+        let colours = match (&self.custom, self.scheme) {
+            (Some(c), _) => repeated(c, stops.len()),
+            (None, Scheme::Hues) => {
+                self.colour_list(&stops.iter().map(|s| u64::from(*s)).collect::<Vec<_>>())
+            }
+            (None, Scheme::Categorical) => repeated(&OKABE_ITO, stops.len()),
+            (None, Scheme::Sequential) => ramp(stops.len()),
+            (None, Scheme::Mono) => self.greys(stops.len()),
+        };
+        if self.jumble {
+            jumbled(colours)
+        } else {
+            colours
+        }
+    }
+
+    /// Colours for `count` arc classes. If `ordered`, the classes represent
+    /// increasing quantities and get a sequential ramp unless a palette says otherwise.
+    pub fn class_colours(&self, count: usize, ordered: bool) -> Vec<String> {
+        match (&self.custom, self.scheme) {
+            (Some(c), _) => repeated(c, count),
+            (None, Scheme::Mono) => self.greys(count),
+            (None, Scheme::Sequential) => ramp(count),
+            (None, _) if ordered => ramp(count),
+            (None, Scheme::Categorical) => repeated(&OKABE_ITO, count),
+            (None, Scheme::Hues) => self.colour_list(&(0..count as u64).collect::<Vec<_>>()),
+        }
+    }
+
+    fn colour_list(&self, keys: &[u64]) -> Vec<String> {
+        //! Create a vector of hex colour codes, one per key (usually a stop), with evenly-spaced
+        //! hues from `start` to `stop` plus a little bit of variance in saturation and lightness.
+        //! The saturation is drawn from an RNG keyed by a hash of `seed` and the key itself,
+        //! so a given stop gets the same colour on every run.
+        let count = keys.len();
+        keys.iter()
+            .enumerate()
+            .map(|(k, key)| {
+                let mut rng = StdRng::seed_from_u64(mix(self.seed, *key));
+                let hue = (self.stop - self.start).mul_add(k as f64 / count as f64, self.start);
+                let sat_var: f64 = rng.gen();
+                let sat = 10.0f64.mul_add(sat_var, self.chroma - 10.0).clamp(0.0, 100.0);
+                let val = match k % 4 {
+                    1 => self.lightness - 5.0,
+                    3 => self.lightness + 5.0,
+                    _ => self.lightness,
+                };
+                hsluv_to_hex((hue.rem_euclid(360.0), sat, val.clamp(0.0, 100.0)))
+            })
+            .collect()
+    }
+
+    fn greys(&self, count: usize) -> Vec<String> {
+        //! Alternate between three shades of grey, centred on `lightness`
+        (0..count)
+            .map(|k| {
+                let val = match k % 3 {
+                    1 => self.lightness - 15.0,
+                    2 => self.lightness + 15.0,
+                    _ => self.lightness,
+                };
+                hsluv_to_hex((0.0, 0.0, val.clamp(0.0, 100.0)))
+            })
+            .collect()
+    }
+}
+
+/// Colour arcs by trip length in stops along `stop_sequence`.
+/// Wrap-around arcs (destination before origin) are counted through the loop.
+pub fn by_stops(
+    patronages: &BTreeMap<(StopId, StopId), Quantity>,
+    stop_sequence: &[StopId],
+    palette: &Palette,
+) -> ArcClasses {
+    let seqi: BTreeMap<StopId, usize> =
+        stop_sequence.iter().enumerate().map(|(i, s)| (*s, i)).collect();
+    let count = stop_sequence.len();
+    let spans = patronages
+        .keys()
+        .filter_map(|(o, d)| {
+            let (from, to) = (seqi.get(o)?, seqi.get(d)?);
+            let span = if to > from { to - from } else { count - from + to };
+            Some(((*o, *d), span as f64))
+        })
+        .collect();
+    binned(&spans, palette, |lo, hi| {
+        if hi - lo < 1.0 {
+            format!("{lo:.0} stop{}", if lo > 1.0 { "s" } else { "" })
+        } else {
+            format!("{lo:.0}–{hi:.0} stops")
+        }
+    })
+}
+
+/// Colour arcs by great-circle distance between origin and destination.
+/// `coords` are (latitude, longitude) in decimal degrees.
+pub fn by_distance(
+    patronages: &BTreeMap<(StopId, StopId), Quantity>,
+    coords: &BTreeMap<StopId, (f64, f64)>,
+    palette: &Palette,
+) -> ArcClasses {
+    let dists = patronages
+        .keys()
+        .filter_map(|(o, d)| {
+            let (from, to) = (coords.get(o)?, coords.get(d)?);
+            Some(((*o, *d), gc_distance(from.0, from.1, to.0, to.1) / 1000.0))
+        })
+        .collect();
+    binned(&dists, palette, |lo, hi| format!("{lo:.1}–{hi:.1} km"))
+}
+
+/// Colour arcs by a category such as ticket type or time of day.
+pub fn by_category(
+    categories: &BTreeMap<(StopId, StopId), String>,
+    palette: &Palette,
+) -> ArcClasses {
+    let mut labels: Vec<String> = categories.values().cloned().collect();
+    labels.sort_unstable();
+    labels.dedup();
+    let classes =
+        categories.iter().filter_map(|(k, v)| Some((*k, labels.binary_search(v).ok()?))).collect();
+    let colours = palette.class_colours(labels.len(), false);
+    ArcClasses { classes, labels, colours }
+}
+
+/// Number of bins for quantitative colouring
+const BINS: usize = 6;
+
+fn binned(
+    values: &BTreeMap<(StopId, StopId), f64>,
+    palette: &Palette,
+    label: impl Fn(f64, f64) -> String,
+) -> ArcClasses {
+    //! Split `values` into equal-width bins between their minimum and maximum.
+    //! Integer-valued data with only a few distinct values gets one bin per value.
+    #![allow(clippy::cast_possible_truncation)]
+    #![allow(clippy::cast_sign_loss)]
+    let min = values.values().copied().fold(f64::INFINITY, f64::min);
+    let max = values.values().copied().fold(f64::NEG_INFINITY, f64::max);
+    if values.is_empty() {
+        return ArcClasses::default();
+    }
+    let integral = values.values().all(|v| v.fract() == 0.0);
+    let (bins, width) = if integral {
+        let width = ((max - min + 1.0) / BINS as f64).ceil();
+        (((max - min + 1.0) / width).ceil() as usize, width)
+    } else {
+        (BINS, ((max - min) / BINS as f64).max(f64::EPSILON))
+    };
+    let classes = values
+        .iter()
+        .map(|(k, v)| (*k, (((v - min) / width).floor() as usize).min(bins - 1)))
+        .collect();
+    let labels = (0..bins)
+        .map(|b| {
+            let lo = (b as f64).mul_add(width, min);
+            let hi = if integral { (lo + width - 1.0).min(max) } else { lo + width };
+            label(lo, hi)
+        })
+        .collect();
+    let colours = palette.class_colours(bins, true);
+    ArcClasses { classes, labels, colours }
+}
+
+/// Load a palette file: one CSS colour per line, blank lines and `//` comments ignored
+pub fn load_palette(path: &Path) -> Result<Vec<String>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Could not read palette file {}", path.display()))?;
+    let colours: Vec<String> = text
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with("//"))
+        .map(String::from)
+        .collect();
+    if colours.is_empty() {
+        bail!("Palette file {} contains no colours", path.display());
+    }
+    Ok(colours)
+}
+
+const fn mix(seed: u64, key: u64) -> u64 {
+    //! Hash `seed` and `key` together (`SplitMix64`'s finaliser), so that nearby keys
+    //! and nearby seeds give unrelated results
+    let mut z = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15).wrapping_add(key);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+fn repeated<T: AsRef<str>>(colours: &[T], count: usize) -> Vec<String> {
+    //! Cycle through `colours` until we have `count` of them
+    colours.iter().cycle().take(count).map(|c| String::from(c.as_ref())).collect()
+}
+
+fn ramp(count: usize) -> Vec<String> {
+    //! Interpolate along the [`VIRIDIS`] anchors, one colour per stop
+    #![allow(clippy::cast_possible_truncation)]
+    #![allow(clippy::cast_sign_loss)]
+    let segments = (VIRIDIS.len() - 1) as f64;
+    (0..count)
+        .map(|k| {
+            let t = if count > 1 { k as f64 / (count - 1) as f64 } else { 0.0 } * segments;
+            let i = (t.floor() as usize).min(VIRIDIS.len() - 2);
+            let frac = t - i as f64;
+            let (a, b) = (VIRIDIS[i], VIRIDIS[i + 1]);
+            let lerp = |x: u8, y: u8| {
+                (f64::from(y) - f64::from(x)).mul_add(frac, f64::from(x)).round() as u8
+            };
+            format!("#{:02x}{:02x}{:02x}", lerp(a.0, b.0), lerp(a.1, b.1), lerp(a.2, b.2))
+        })
+        .collect()
+}
+
+fn jumbled<T>(input: Vec<T>) -> Vec<T>
+where
+    T: Clone,
+{
+    //! If `input.len() >= 2`, returns a copy of `input` with its elements permuted in a star pattern.
+    //! Otherwise, returns `input`.
+
+    let len = input.len();
+
+    if len < 2 {
+        input
+    } else if len == 4 {
+        vec![input[1].clone(), input[3].clone(), input[0].clone(), input[2].clone()]
+    } else if len == 6 {
+        vec![
+            input[1].clone(),
+            input[3].clone(),
+            input[5].clone(),
+            input[0].clone(),
+            input[2].clone(),
+            input[4].clone(),
+        ]
+    } else {
+        // want g, L to be co-prime for a star pattern
+        // if m, n are coprime then more coprime pairs can be generated:
+        // (2m - n, m) and (2m + n, m) and (m + 2n, n)
+        // always coprime if m = n - 1 (for m >= 3)
+        let g = match len % 3 {
+            1 => len / 3, // (m + 2n, n) => 3k + 1, k = n
+            _ => match len % 9 {
+                6 => len / 3 - 1, // 3(3k-1) e.g. 15 so L/3 + 1 is div. by 3
+                _ => len / 3 + 1, // (2m + n, m) => 3k + 2, k = n AND ALSO other 3k's
+            },
+        };
+
+        let mut out = Vec::with_capacity(len);
+        for i in 0..len {
+            out.push(input[(g * (i + g)) % len].clone());
+        }
+        out
+    }
+}
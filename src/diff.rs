@@ -0,0 +1,203 @@
+//! Compare a route's patronage between two datasets, e.g. two months
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use anyhow::Result;
+
+use crate::geometry::{Arc, Body, Stops};
+use crate::gtfs::{Quantity, StopId};
+use crate::visualise::{escape, load_css, make_legend, name_lines, segment_loads, sum_up, Layout};
+
+pub fn percent_change(before: Quantity, after: Quantity) -> String {
+    //! The change from `before` to `after` as a percentage, e.g. "+12%" or "-5%",
+    //! or "new" if there was nothing before
+    match (before, after) {
+        _ if before == after => String::from("±0%"),
+        (0, _) => String::from("new"),
+        _ => format!("{:+.0}%", 100.0 * (f64::from(after) - f64::from(before)) / f64::from(before)),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+#[allow(clippy::too_many_lines)]
+/// Draw the change in a single route's patronage from `before` to `after`.
+/// Stops are laid out just as for [`visualise_one`](crate::visualise::visualise_one),
+/// in order of `stop_sequence`, which both are drawn on; trips between stops not in it are left out.
+/// Arcs are as wide as the change in passengers between their stops,
+/// and classed `growth` or `decline`, as are the bars for the change in load.
+/// Stop labels give the percentage change at each stop.
+/// `distances` between consecutive stops, in metres, are needed for distance spacing.
+pub fn diff_one(
+    before: &BTreeMap<(StopId, StopId), Quantity>,
+    after: &BTreeMap<(StopId, StopId), Quantity>,
+    stop_sequence: &[StopId],
+    stop_names: &BTreeMap<StopId, String>,
+    route_name: &str,
+    direction: &str,
+    before_label: &str,
+    after_label: &str,
+    css_path: Option<&PathBuf>,
+    layout: &Layout,
+    distances: Option<&[f64]>,
+) -> Result<String> {
+    let seqi: BTreeMap<StopId, usize> =
+        stop_sequence.iter().enumerate().map(|(i, stop)| (*stop, i)).collect();
+    let stop_count = stop_sequence.len();
+    let space = layout.space;
+
+    // Only trips between stops in the sequence can be drawn, or counted
+    let in_sequence =
+        |m: &BTreeMap<(StopId, StopId), Quantity>| -> BTreeMap<(StopId, StopId), Quantity> {
+            m.iter()
+                .filter(|((from, to), _)| seqi.contains_key(from) && seqi.contains_key(to))
+                .map(|(k, v)| (*k, *v))
+                .collect()
+        };
+    let (before, after) = (in_sequence(before), in_sequence(after));
+    let (boards_before, alights_before) = sum_up(&before);
+    let (boards_after, alights_after) = sum_up(&after);
+    let count = |m: &BTreeMap<StopId, Quantity>, stop: &StopId| *m.get(stop).unwrap_or(&0);
+
+    // names are escaped only once they're shortened, so that entities aren't cut in half
+    let name = |stop: StopId| stop_names.get(&stop).cloned().unwrap_or_else(|| stop.to_string());
+    let label_lines: Vec<Vec<String>> = stop_sequence
+        .iter()
+        .map(|stop| {
+            let mut lines = name_lines(&name(*stop), layout.name_length, layout.wrap_names);
+            lines.push(format!(
+                "{} alightings | {} boardings",
+                percent_change(count(&alights_before, stop), count(&alights_after, stop)),
+                percent_change(count(&boards_before, stop), count(&boards_after, stop))
+            ));
+            lines
+        })
+        .collect();
+    let stops = Stops::new(layout, &label_lines, distances);
+
+    // {(from index, to index) : (before, after)}, for each pair that changed
+    let mut changes: BTreeMap<(usize, usize), (Quantity, Quantity)> = BTreeMap::new();
+    for key in before.keys().chain(after.keys()) {
+        let (b, a) = (*before.get(key).unwrap_or(&0), *after.get(key).unwrap_or(&0));
+        let (from_idx, to_idx) = (seqi[&key.0], seqi[&key.1]);
+        if from_idx != to_idx && a != b {
+            changes.insert((from_idx, to_idx), (b, a));
+        }
+    }
+    let arc_height = stops.arc_height(changes.keys().copied());
+
+    // the tightest squeeze: the change at a stop's origins and the next stop's destinations
+    let mut out_change = vec![0; stop_count];
+    let mut in_change = vec![0; stop_count];
+    for ((from_idx, to_idx), (b, a)) in &changes {
+        out_change[*from_idx] += a.abs_diff(*b);
+        in_change[*to_idx] += a.abs_diff(*b);
+    }
+    let squeeze = stops.squeeze(&out_change, &in_change);
+    // with no change at all, any scale will do (and a tiny one makes the legend enormous)
+    let squeeze = if squeeze > 0.0 { squeeze } else { 1.0 };
+    let px = |change: Quantity| f64::from(change) / squeeze;
+
+    let key = (
+        String::from("change in passengers"),
+        vec![
+            (String::from("growth"), format!("more passengers in {after_label}")),
+            (String::from("decline"), format!("fewer passengers in {after_label}")),
+        ],
+    );
+    let (legend, legend_height) = make_legend(Some(&key), squeeze * space, space, &[], None)?;
+
+    let loads_before = segment_loads(&before, stop_sequence);
+    let loads_after = segment_loads(&after, stop_sequence);
+    let tallest =
+        loads_before.iter().zip(&loads_after).map(|(b, a)| px(a.abs_diff(*b))).fold(0.0, f64::max);
+    let widest = changes.values().map(|(b, a)| px(a.abs_diff(*b))).fold(0.0, f64::max);
+    let geometry = stops.fit(layout, legend_height, arc_height, widest, &label_lines, tallest);
+
+    let widths = changes.iter().map(|(pair, (b, a))| (*pair, px(a.abs_diff(*b)))).collect();
+    let arcs = geometry.arcs(&widths, None);
+    let path = |arc: &Arc| {
+        let (b, a) = changes[&(arc.from_idx, arc.to_idx)];
+        let class = if a > b { "growth" } else { "decline" };
+        format!(
+            r#"<path class="arc {class}" data-from="{}" data-to="{}" data-qty="{}" d="{}" stroke-width="{:.5}"><title>from: {}
+to: {}
+{before_label}: {b}
+{after_label}: {a}
+change: {:+} ({})</title></path>
+"#,
+            arc.from_idx,
+            arc.to_idx,
+            i64::from(a) - i64::from(b),
+            arc.d,
+            arc.width,
+            escape(&name(stop_sequence[arc.from_idx])),
+            escape(&name(stop_sequence[arc.to_idx])),
+            i64::from(a) - i64::from(b),
+            percent_change(b, a),
+        )
+    };
+    let paths_rev: String = arcs.wraparounds.iter().map(path).collect();
+    let paths_fwd: String = arcs.forwards.iter().map(path).collect();
+
+    // Stops, their labels, and the change in load after each
+    let mut midline = geometry.mainline();
+    let mut labels = String::new();
+    let mut bargraph = String::new();
+    for (idx, (stop, lines)) in stop_sequence.iter().zip(label_lines.iter()).enumerate() {
+        labels.push_str(&geometry.label(idx, lines)?);
+
+        let (b, a) = (loads_before[idx], loads_after[idx]);
+        let class = match a.cmp(&b) {
+            Ordering::Greater => " growth",
+            Ordering::Less => " decline",
+            Ordering::Equal => "",
+        };
+        bargraph.push_str(&geometry.bar(idx, px(a.abs_diff(b)), class, ""));
+        bargraph.push_str(&geometry.bar_text(
+            idx,
+            &format!("{:+} ({})", i64::from(a) - i64::from(b), percent_change(b, a)),
+        )?);
+
+        let data = format!(r#" data-name="{}""#, escape(&name(*stop)));
+        midline.push_str(&geometry.marker(idx, "", &data));
+    }
+
+    let page = geometry.page();
+
+    let total = |m: &BTreeMap<StopId, Quantity>| -> Quantity { m.values().sum() };
+    let title = format!(
+        r#"<text class="title" x="{0}" y="100">{4} {5}</text>
+    <text class="subtitle" x="{0}" y="150">{after_label} compared to {before_label}</text>
+    <text class="subtitle" x="{0}" y="195">{1} boardings, {2} on {3}</text>"#,
+        page.width / 2.0,
+        total(&boards_after),
+        percent_change(total(&boards_before), total(&boards_after)),
+        total(&boards_before),
+        escape(route_name),
+        escape(direction),
+    );
+
+    let body = Body { paths_rev, paths_fwd, labels, bargraph, midline };
+    Ok(page.svg(&load_css(css_path)?, &body, &title, &legend, ""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percent_changes() {
+        assert_eq!(percent_change(200, 230), "+15%");
+        assert_eq!(percent_change(200, 150), "-25%");
+        assert_eq!(percent_change(10, 0), "-100%");
+        assert_eq!(percent_change(7, 7), "±0%");
+    }
+
+    #[test]
+    fn percent_change_from_nothing() {
+        assert_eq!(percent_change(0, 0), "±0%");
+        assert_eq!(percent_change(0, 5), "new");
+    }
+}
@@ -0,0 +1,232 @@
+//! Tables of a single route's patronage, for analysis in other tools
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::gtfs::{Leg, Quantity, StopId};
+use crate::visualise::{segment_loads, sum_up};
+
+/// One cell of an origin-destination matrix
+#[derive(Debug, Serialize)]
+pub struct OdRow<'a> {
+    /// Position of the origin in the stop sequence, from 0
+    pub origin_seq: usize,
+    /// Stop ID of the origin
+    pub origin_stop: StopId,
+    /// Name of the origin
+    pub origin_name: &'a str,
+    /// Position of the destination in the stop sequence, from 0
+    pub destination_seq: usize,
+    /// Stop ID of the destination
+    pub destination_stop: StopId,
+    /// Name of the destination
+    pub destination_name: &'a str,
+    /// Passengers from the origin to the destination
+    pub passengers: Quantity,
+}
+
+pub fn od_rows<'a>(
+    patronages: &BTreeMap<(StopId, StopId), Quantity>,
+    stop_sequence: &[StopId],
+    stop_names: &'a BTreeMap<StopId, String>,
+) -> Vec<OdRow<'a>> {
+    //! The whole OD matrix in long form, zeroes included,
+    //! with origins and then destinations in `stop_sequence` order
+    let name = |stop: &StopId| stop_names.get(stop).map_or("", String::as_str);
+    let mut rows = Vec::with_capacity(stop_sequence.len() * stop_sequence.len());
+    for (origin_seq, origin) in stop_sequence.iter().enumerate() {
+        for (destination_seq, destination) in stop_sequence.iter().enumerate() {
+            rows.push(OdRow {
+                origin_seq,
+                origin_stop: *origin,
+                origin_name: name(origin),
+                destination_seq,
+                destination_stop: *destination,
+                destination_name: name(destination),
+                passengers: *patronages.get(&(*origin, *destination)).unwrap_or(&0),
+            });
+        }
+    }
+    rows
+}
+
+/// A stop on a route, and how busy it is
+#[derive(Debug, Serialize)]
+pub struct StopRow<'a> {
+    /// Position in the stop sequence, from 0
+    pub seq: usize,
+    /// Stop ID
+    pub stop_id: StopId,
+    /// Stop name
+    pub stop_name: &'a str,
+    /// Latitude
+    pub stop_lat: f64,
+    /// Longitude
+    pub stop_lon: f64,
+    /// Passengers boarding here
+    pub boardings: Quantity,
+    /// Passengers alighting here
+    pub alightings: Quantity,
+    /// Passengers on board leaving here, towards the next stop
+    pub departing_load: Quantity,
+}
+
+pub fn stop_rows<'a>(
+    patronages: &BTreeMap<(StopId, StopId), Quantity>,
+    stop_sequence: &[StopId],
+    stop_names: &'a BTreeMap<StopId, String>,
+    coords: &BTreeMap<StopId, (f64, f64)>,
+) -> Vec<StopRow<'a>> {
+    //! One row per stop in `stop_sequence` order.
+    //! The last stop's departing load is whoever is still on board around the loop.
+    let (boardings, alightings) = sum_up(patronages);
+    let loads = segment_loads(patronages, stop_sequence);
+    stop_sequence
+        .iter()
+        .zip(loads)
+        .enumerate()
+        .map(|(seq, (stop, departing_load))| {
+            let (stop_lat, stop_lon) = coords.get(stop).copied().unwrap_or((f64::NAN, f64::NAN));
+            StopRow {
+                seq,
+                stop_id: *stop,
+                stop_name: stop_names.get(stop).map_or("", String::as_str),
+                stop_lat,
+                stop_lon,
+                boardings: *boardings.get(stop).unwrap_or(&0),
+                alightings: *alightings.get(stop).unwrap_or(&0),
+                departing_load,
+            }
+        })
+        .collect()
+}
+
+pub fn geojson(
+    patronages: &BTreeMap<(StopId, StopId), Quantity>,
+    stop_sequence: &[StopId],
+    stop_names: &BTreeMap<StopId, String>,
+    coords: &BTreeMap<StopId, (f64, f64)>,
+    legs: &[Leg],
+) -> Result<Vec<u8>> {
+    //! A `GeoJSON` `FeatureCollection` of the route's stops (as points, with the same properties
+    //! as [`stop_rows`]) and of the segments between them (as lines, following `legs`,
+    //! with the load on board). The segment around the loop is only there if anyone's on it.
+    let rows = stop_rows(patronages, stop_sequence, stop_names, coords);
+    let position = |(lat, lon): (f64, f64)| json!([lon, lat]);
+
+    let mut features: Vec<Value> = rows
+        .iter()
+        .map(|row| {
+            json!({
+                "type": "Feature",
+                "geometry": {"type": "Point", "coordinates": position((row.stop_lat, row.stop_lon))},
+                "properties": row,
+            })
+        })
+        .collect();
+
+    for (i, (from, to)) in rows.iter().zip(rows.iter().cycle().skip(1)).enumerate() {
+        let around = i + 1 == rows.len();
+        if around && from.departing_load == 0 {
+            continue;
+        }
+        let leg = legs.get(i).filter(|_| !around);
+        let path: Vec<Value> = leg.map_or_else(
+            || vec![position((from.stop_lat, from.stop_lon)), position((to.stop_lat, to.stop_lon))],
+            |leg| leg.path.iter().copied().map(position).collect(),
+        );
+        features.push(json!({
+            "type": "Feature",
+            "geometry": {"type": "LineString", "coordinates": path},
+            "properties": {
+                "seq": from.seq,
+                "from_stop": from.stop_id,
+                "from_name": from.stop_name,
+                "to_stop": to.stop_id,
+                "to_name": to.stop_name,
+                "load": from.departing_load,
+                "distance_m": leg.map(|leg| leg.distance),
+                "loop": around,
+            },
+        }));
+    }
+
+    Ok(serde_json::to_vec(&json!({"type": "FeatureCollection", "features": features}))?)
+}
+
+pub fn to_csv<T: Serialize>(rows: &[T]) -> Result<Vec<u8>> {
+    //! Rows as CSV, with a header
+    let mut wtr = csv::Writer::from_writer(Vec::new());
+    for row in rows {
+        wtr.serialize(row)?;
+    }
+    Ok(wtr.into_inner()?)
+}
+
+pub fn to_json<T: Serialize>(rows: &[T]) -> Result<Vec<u8>> {
+    //! Rows as a JSON array of objects (what pandas calls "records")
+    Ok(serde_json::to_vec_pretty(rows)?)
+}
+
+#[cfg(feature = "parquet")]
+pub fn od_parquet(rows: &[OdRow]) -> Result<Vec<u8>> {
+    //! OD rows as a Parquet file, with the same columns as the CSV
+    use std::sync::Arc;
+
+    use anyhow::Context;
+
+    use parquet::data_type::{ByteArray, ByteArrayType, Int64Type};
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::writer::SerializedFileWriter;
+    use parquet::schema::parser::parse_message_type;
+
+    let schema = Arc::new(parse_message_type(
+        "message od {
+            REQUIRED INT64 origin_seq;
+            REQUIRED INT64 origin_stop;
+            REQUIRED BYTE_ARRAY origin_name (UTF8);
+            REQUIRED INT64 destination_seq;
+            REQUIRED INT64 destination_stop;
+            REQUIRED BYTE_ARRAY destination_name (UTF8);
+            REQUIRED INT64 passengers;
+        }",
+    )?);
+
+    let seq = |i: usize| i64::try_from(i).unwrap_or(i64::MAX);
+    let ints: [Vec<i64>; 5] = [
+        rows.iter().map(|r| seq(r.origin_seq)).collect(),
+        rows.iter().map(|r| i64::from(r.origin_stop)).collect(),
+        rows.iter().map(|r| seq(r.destination_seq)).collect(),
+        rows.iter().map(|r| i64::from(r.destination_stop)).collect(),
+        rows.iter().map(|r| i64::from(r.passengers)).collect(),
+    ];
+    let names: [Vec<ByteArray>; 2] = [
+        rows.iter().map(|r| ByteArray::from(r.origin_name)).collect(),
+        rows.iter().map(|r| ByteArray::from(r.destination_name)).collect(),
+    ];
+
+    let mut buf = Vec::new();
+    let mut writer =
+        SerializedFileWriter::new(&mut buf, schema, Arc::new(WriterProperties::builder().build()))?;
+    let mut group = writer.next_row_group()?;
+    // columns in schema order: names are the third and sixth
+    let (mut int_cols, mut name_cols) = (ints.iter(), names.iter());
+    let mut column = 0;
+    while let Some(mut col) = group.next_column()? {
+        if column == 2 || column == 5 {
+            let values = name_cols.next().context("Too many Parquet columns")?;
+            col.typed::<ByteArrayType>().write_batch(values, None, None)?;
+        } else {
+            let values = int_cols.next().context("Too many Parquet columns")?;
+            col.typed::<Int64Type>().write_batch(values, None, None)?;
+        }
+        col.close()?;
+        column += 1;
+    }
+    group.close()?;
+    writer.close()?;
+    Ok(buf)
+}
@@ -0,0 +1,487 @@
+//! Where everything goes in an arc diagram: the stops along their line, the arcs stacked up
+//! at their stops, the labels and bargraph underneath, and the page around it all.
+//! Single months, diffs and animations are all laid out by these, so they look alike.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+
+use anyhow::Result;
+
+use crate::gtfs::Quantity;
+use crate::visualise::{escape, Layout, Orientation, MIN_WIDTH};
+
+/// Approximate width of a character in label text, in ems
+const CHAR_WIDTH: f64 = 0.6;
+
+/// Where the stops go along the diagram, in pixels
+#[derive(Clone, Debug)]
+pub struct Stops {
+    /// Position of each stop
+    pub x: Vec<f64>,
+    /// The gap after each stop; the last one is the way around the loop
+    pub gaps: Vec<f64>,
+    /// How far along a copy of the route one loop along is
+    pub period: f64,
+    /// Distance between stops (on average, with distance spacing)
+    pub between: f64,
+    /// Minimum gap between stops' arcs
+    pub min_gap: f64,
+    /// Padding at the left and right edges
+    pub extra: f64,
+    /// Whether stops are spaced by distance rather than evenly
+    pub uneven: bool,
+}
+
+impl Stops {
+    pub fn new(layout: &Layout, label_lines: &[Vec<String>], distances: Option<&[f64]>) -> Self {
+        //! Lay out a stop for each of `label_lines` (its label, a line per entry).
+        //! Without `distances` (in metres, one per pair of consecutive stops), stops are evenly spaced;
+        //! with them, they're spaced in proportion, but never closer than their labels need.
+        let stop_count = label_lines.len();
+        let space = layout.space;
+        let between = layout.between * space;
+        let min_gap = layout.min_gap * space;
+        let extra = layout.edge * space;
+        let min_spacing = layout.min_spacing.map_or_else(
+            || {
+                let lines = label_lines.iter().map(Vec::len).max().unwrap_or(1);
+                (lines as f64).mul_add(layout.font_size, min_gap)
+            },
+            |m| m * space,
+        );
+
+        let distances = distances.filter(|d| d.len() + 1 == stop_count);
+        let x: Vec<f64> = match distances {
+            None => (0..stop_count).map(|i| (i as f64).mul_add(between, extra)).collect(),
+            Some(distances) => {
+                let total: f64 = distances.iter().sum();
+                let per_metre =
+                    if total > 0.0 { (stop_count as f64 - 1.0) * between / total } else { 0.0 };
+                let mut x = extra;
+                std::iter::once(x)
+                    .chain(distances.iter().map(|d| {
+                        x += (d * per_metre).max(min_spacing);
+                        x
+                    }))
+                    .collect()
+            }
+        };
+        let last_x = x.last().copied().unwrap_or(extra);
+        let gaps = (0..stop_count).map(|i| x.get(i + 1).map_or(between, |n| n - x[i])).collect();
+        // wraparound arcs head off to a copy of the route one loop along
+        let period = last_x - extra + between;
+
+        Self { x, gaps, period, between, min_gap, extra, uneven: distances.is_some() }
+    }
+
+    pub fn last_x(&self) -> f64 {
+        //! Position of the last stop
+        self.x.last().copied().unwrap_or(self.extra)
+    }
+
+    pub fn span(&self, from_idx: usize, to_idx: usize) -> f64 {
+        //! How far an arc reaches; wraparounds span the gap "through the loop"
+        if to_idx > from_idx {
+            self.x[to_idx] - self.x[from_idx]
+        } else {
+            self.x[to_idx] + self.period - self.x[from_idx]
+        }
+    }
+
+    pub fn arc_height(&self, pairs: impl Iterator<Item = (usize, usize)>) -> f64 {
+        //! Height of the tallest of the arcs between `pairs` of stops (by sequence index)
+        let span_max = pairs
+            .filter(|(from_idx, to_idx)| from_idx != to_idx)
+            .map(|(from_idx, to_idx)| self.span(from_idx, to_idx))
+            .fold(self.between, f64::max);
+        // (arcs start and end either side of their stops, so allow for up to one more gap)
+        (span_max + self.between) * 0.5
+    }
+
+    pub fn squeeze(&self, out: &[Quantity], into: &[Quantity]) -> f64 {
+        //! Passengers per pixel that fit every stop's arcs into its gap:
+        //! the tightest squeeze is a stop's `out`going passengers and the next stop's `into`coming
+        let room = self.between - self.min_gap;
+        if !self.uneven {
+            let max = |q: &[Quantity]| q.iter().copied().max().unwrap_or(0);
+            return f64::from(max(out) + max(into)) / room;
+        }
+        let stop_count = out.len();
+        (0..stop_count)
+            .map(|i| {
+                f64::from(out[i] + into[(i + 1) % stop_count])
+                    / (self.gaps[i] - self.min_gap).max(f64::EPSILON)
+            })
+            .fold(0.0, f64::max)
+    }
+
+    pub fn fit(
+        self,
+        layout: &Layout,
+        top: f64,
+        arc_height: f64,
+        widest: f64,
+        label_lines: &[Vec<String>],
+        tallest: f64,
+    ) -> Geometry {
+        //! Fit the arcs (as high as `arc_height` and as wide as `widest`) underneath `top`,
+        //! then the labels and a bargraph as tall as `tallest` underneath them
+        let Layout { space, font_size, orientation, compact, .. } = *layout;
+        // In compact mode, flatten every arc by the same factor so the tallest fits
+        let squash = compact.map_or(1.0, |c| (c / arc_height).min(1.0));
+
+        // the text section fits the longest label above the tallest bar
+        let text_section = layout.text_section.map_or_else(
+            || {
+                let longest =
+                    label_lines.iter().flatten().map(|l| l.chars().count()).max().unwrap_or(0);
+                (longest as f64 * CHAR_WIDTH).mul_add(font_size, space + tallest.max(space))
+            },
+            |t| t * space,
+        );
+
+        // arcs are stroked on their centreline, so leave room for the widest to stick out the top
+        let main_height = top + squash.mul_add(arc_height, widest / 2.0);
+        Geometry {
+            width: self.last_x() + self.extra,
+            height: text_section + main_height,
+            stops: self,
+            space,
+            font_size,
+            orientation,
+            top,
+            squash,
+            main_height,
+            text_section,
+        }
+    }
+}
+
+/// Where everything goes on the body of the diagram, in pixels, as if it were landscape
+#[derive(Clone, Debug)]
+pub struct Geometry {
+    /// Where the stops go
+    pub stops: Stops,
+    /// Spacing unit
+    pub space: f64,
+    /// Font size of the stop labels
+    pub font_size: f64,
+    /// Which way round the page goes
+    pub orientation: Orientation,
+    /// Height of whatever's above the body, i.e. the title and legend
+    pub top: f64,
+    /// How much every arc is flattened
+    pub squash: f64,
+    /// Where the line through the stops goes
+    pub main_height: f64,
+    /// Height of the section for stop names and the bargraph
+    pub text_section: f64,
+    /// Width of the body
+    pub width: f64,
+    /// Height of the body, down to the foot of the bargraph
+    pub height: f64,
+}
+
+/// An arc's place on the diagram
+#[derive(Clone, Debug)]
+pub struct Arc {
+    /// Sequence index of its origin
+    pub from_idx: usize,
+    /// Sequence index of its destination
+    pub to_idx: usize,
+    /// Stroke width
+    pub width: f64,
+    /// Its path's data
+    pub d: String,
+}
+
+/// Every arc's place on the diagram, in the order they're drawn
+#[derive(Clone, Debug, Default)]
+pub struct Arcs {
+    /// Arcs which go around the loop; these go underneath the rest
+    pub wraparounds: Vec<Arc>,
+    /// Arcs from earlier stops to later ones
+    pub forwards: Vec<Arc>,
+    /// Total width of the wraparounds leaving each stop
+    pub wrapped: Vec<f64>,
+}
+
+/// The page around the body, and how the body is placed on it
+#[derive(Clone, Debug)]
+pub struct Page {
+    /// Anything that needs defining for the body, i.e. its clip path
+    pub defs: String,
+    /// The body's transform
+    pub transform: String,
+    /// The body's clip path attribute, if any
+    pub clip: &'static str,
+    /// Width, at least [`MIN_WIDTH`]
+    pub width: f64,
+    /// Height, down to the foot of the body
+    pub height: f64,
+}
+
+/// What's drawn on the body, from the bottom up
+#[derive(Clone, Debug, Default)]
+pub struct Body {
+    /// Arcs which go around the loop
+    pub paths_rev: String,
+    /// Every other arc
+    pub paths_fwd: String,
+    /// Stop labels
+    pub labels: String,
+    /// Bars for the load, and their numbers
+    pub bargraph: String,
+    /// The line through the stops, and their markers
+    pub midline: String,
+}
+
+impl Page {
+    pub fn svg(&self, css: &str, body: &Body, title: &str, legend: &str, footer: &str) -> String {
+        //! The whole SVG document: the `body` on this page, and the header and footer around it
+        format!(
+            // glorious hack: include_str! is eagerly evaluated
+            include_str!("template.svg"),
+            self.width,
+            self.height,
+            self.defs,
+            css,
+            self.transform,
+            self.clip,
+            body.paths_rev,
+            body.paths_fwd,
+            body.labels,
+            body.bargraph,
+            body.midline,
+            title,
+            legend,
+            footer
+        )
+    }
+}
+
+impl Geometry {
+    pub fn arcs(&self, widths: &BTreeMap<(usize, usize), f64>, starts: Option<&[f64]>) -> Arcs {
+        //! Stack up arcs of `widths` {(from index, to index) : stroke width} at their stops.
+        //! Forward arcs from each stop fill inwards from `starts` (by default, all the arcs from it).
+        let stop_count = self.stops.x.len();
+        let x_of = &self.stops.x;
+        let (space, squash, doc_height, y2) =
+            (self.space, self.squash, self.height, self.main_height);
+        let text_section = self.text_section;
+
+        /* Reverse arc layout has to be in a separate set of loops to forwards arc layout
+         * Reverse arcs need to be laid out in the following order (numbers are indexes):
+         * 1->0, 2->1, ... 2->0, 3->1, ... k-1 -> 0, where k is the number of stops
+         * But forward arcs want a different order: 0 -> 1, 0 -> 2, ... 1 -> 2, ... k-2 -> k-1.
+         * (Reverse arcs lay out tallest-first, forwards arcs lay out shortest-first).
+         */
+        let mut arcs = Arcs { wrapped: vec![0.0; stop_count], ..Arcs::default() };
+        let mut dest_subtotals: Vec<f64> = vec![0.0; stop_count];
+
+        for offset in 1..stop_count {
+            for to_idx in 0..(stop_count - offset) {
+                let from_idx = to_idx + offset;
+                let Some(width) = widths.get(&(from_idx, to_idx)).copied() else {
+                    continue;
+                };
+
+                // need to figure out arcs in/out of the page, and have two of them - "wrap around"
+                // these need to be two-arc paths!
+                let to_dest = dest_subtotals[to_idx];
+                let from_orig = arcs.wrapped[from_idx];
+
+                let x1_right = x_of[from_idx] + (from_orig + width / 2.0) + space / 50.0;
+                let x2_left = x_of[to_idx] - (width / 2.0 + to_dest + space / 50.0);
+                let x2_right = x2_left + self.stops.period;
+                let x1_left = x1_right - self.stops.period;
+
+                let d = format!(
+                    "M{:.5} {} v{} A1 {} 0 1 1 {:.5} {} v{} M{:.5} {} v{} A1 {} 0 1 1 {:.5} {} v{}",
+                    x1_right,
+                    doc_height,
+                    -text_section,
+                    squash,
+                    x2_right,
+                    y2,
+                    text_section,
+                    x1_left,
+                    doc_height,
+                    -text_section,
+                    squash,
+                    x2_left,
+                    y2,
+                    text_section,
+                );
+                arcs.wraparounds.push(Arc { from_idx, to_idx, width, d });
+
+                arcs.wrapped[from_idx] += width;
+                dest_subtotals[to_idx] += width;
+            }
+        }
+
+        /* Now the "forward-arc" case
+         * Our visiting order here is of course 0 -> 1, 0 -> 2, ... 1 -> 2, ... k-2 -> k-1
+         */
+        for from_idx in 0..stop_count {
+            let orig_total = starts.map_or_else(
+                || {
+                    widths
+                        .iter()
+                        .filter(|((f, t), _)| *f == from_idx && *t != from_idx)
+                        .map(|(_, w)| w)
+                        .sum()
+                },
+                |s| s[from_idx],
+            );
+
+            // we're going outside-in here so the wraparound subtotals aren't relevant to us
+            // and due to how we iterate, we only need the scalar here
+            let mut orig_subtotal = 0.0;
+
+            for to_idx in (from_idx + 1)..stop_count {
+                let Some(width) = widths.get(&(from_idx, to_idx)).copied() else {
+                    continue;
+                };
+                let dst = dest_subtotals[to_idx];
+
+                let x1 =
+                    x_of[from_idx] + (orig_total - (orig_subtotal + width / 2.0)) + space / 50.0;
+                let x2 = x_of[to_idx] - (width / 2.0 + dst + space / 50.0);
+
+                let d = format!(
+                    "m{:.5} {} v{} A1 {} 0 1 1 {:.5} {} v{}",
+                    x1, doc_height, -text_section, squash, x2, y2, text_section,
+                );
+                arcs.forwards.push(Arc { from_idx, to_idx, width, d });
+                dest_subtotals[to_idx] += width;
+
+                orig_subtotal += width;
+            }
+        }
+        arcs
+    }
+
+    pub fn mainline(&self) -> String {
+        //! The line the stops are on
+        format!(
+            r#"<line class="mainline" x1="{}" x2="{}" y1="{}" y2="{}" />"#,
+            self.stops.extra,
+            self.stops.last_x(),
+            self.main_height,
+            self.main_height
+        )
+    }
+
+    pub fn marker(&self, idx: usize, classes: &str, data: &str) -> String {
+        //! The circle marking a stop, with any extra `classes` and `data` attributes
+        format!(
+            r#"<circle class="markers{classes}" data-stop="{idx}"{data} cx="{}" cy="{}" r="{}" />"#,
+            self.stops.x[idx],
+            self.main_height,
+            self.space / 4.0
+        )
+    }
+
+    pub fn label(&self, idx: usize, lines: &[String]) -> Result<String> {
+        //! A stop's label, running down from the line, a line of text per entry of `lines`
+        let font_size = self.font_size;
+        let t_x = self.stops.x[idx] - self.space / 8.0;
+        let t_y = self.main_height + self.space / 2.0;
+        let (first, rest) = lines.split_first().map_or(("", &[][..]), |(f, r)| (f.as_str(), r));
+        let first = escape(first);
+        let mut tspans = String::new();
+        for (k, line) in rest.iter().enumerate() {
+            let t_yk = (k as f64 + 1.0).mul_add(font_size, t_y);
+            write!(tspans, r#"<tspan x="{t_x}" y="{t_yk}">{}</tspan>"#, escape(line))?;
+        }
+
+        let mut label = String::new();
+        for t_c in &["keyline", "foreground"] {
+            write!(
+                label,
+                r#"<text class="{t_c}" font-size="{font_size:.1}" text-anchor="end" transform="rotate(270,{t_x},{t_y})" x="{t_x}" y="{t_y}">{first}{tspans}</text>"#,
+                t_c = *t_c,
+            )?;
+        }
+        Ok(label)
+    }
+
+    pub fn bar(&self, idx: usize, height: f64, classes: &str, animation: &str) -> String {
+        //! The bar for the load between a stop and the next, `height` pixels tall,
+        //! with any extra `classes` and any `animation` of it
+        let b_x = self.stops.x[idx] + self.stops.gaps[idx] / 2.0;
+        let close =
+            if animation.is_empty() { String::from("/>") } else { format!(">{animation}</line>") };
+        format!(
+            r#"<line class="bargraph{}" stroke-width="{}" x1="{}" x2="{}" y1="{}" y2="{}" {close}"#,
+            classes,
+            self.stops.gaps[idx],
+            b_x,
+            b_x,
+            self.height,
+            self.height - height,
+        )
+    }
+
+    pub fn bar_text(&self, idx: usize, text: &str) -> Result<String> {
+        //! The numbers on a stop's bar
+        let loopy = if idx + 1 == self.stops.x.len() {
+            // anticlockwise open circle arrow
+            "&#8634; "
+        } else {
+            ""
+        };
+
+        // in portrait, turn the numbers back upright and start them at the base of the bar
+        let b_x = self.stops.x[idx] + self.stops.gaps[idx] / 2.0;
+        let b_ty = self.height - self.space / 5.0;
+        let b_attrs = match self.orientation {
+            Orientation::Landscape => String::from(r#"text-anchor="middle""#),
+            Orientation::Portrait => {
+                format!(r#"style="text-anchor: start" transform="rotate(-90,{b_x},{b_ty})""#)
+            }
+        };
+
+        let mut bar_text = String::new();
+        for t_c in &["keyline", "foreground"] {
+            write!(
+                bar_text,
+                r#"<text class="{} bartxt" {} x="{}" y="{}">{}{}</text>"#,
+                *t_c, b_attrs, b_x, b_ty, loopy, text
+            )?;
+        }
+        Ok(bar_text)
+    }
+
+    pub fn page(&self) -> Page {
+        //! The page the body goes on, under the title and legend
+
+        /* Portrait reuses all of the above, turned a quarter-turn clockwise underneath the header:
+         * the bottom of the bargraph goes to the left edge and the first stop to the top.
+         * The title and legend aren't turned, so they're laid out for the page as it ends up.
+         * Wraparound arcs leave the body past its first and last stops, which in portrait
+         * would run over the legend and footer; the body is clipped to its own area instead.
+         */
+        let (width, height, top) = (self.width, self.height, self.top);
+        let page = match self.orientation {
+            Orientation::Landscape => Page {
+                defs: String::new(),
+                transform: String::from("translate(0,0)"),
+                clip: "",
+                width,
+                height,
+            },
+            Orientation::Portrait => Page {
+                defs: format!(
+                    r#"<clipPath id="body"><rect x="0" y="0" width="{width}" height="{height}" /></clipPath>"#
+                ),
+                transform: format!("matrix(0,1,-1,0,{height},{top})"),
+                clip: r#" clip-path="url(#body)""#,
+                width: height - top,
+                height: top + width,
+            },
+        };
+        Page { width: page.width.max(MIN_WIDTH), ..page }
+    }
+}
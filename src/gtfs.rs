@@ -5,7 +5,8 @@ use std::iter::Iterator;
 use std::path::PathBuf;
 
 use anyhow::{bail, Context, Result};
-use rusqlite::Connection;
+use log::warn;
+use rusqlite::{named_params, Connection, OptionalExtension};
 use serde::{Deserialize, Serialize};
 use serde_rusqlite::from_rows;
 
@@ -70,8 +71,9 @@ struct FirstLastSeq {
     qty: Quantity,
 }
 
-pub fn load_gtfs(db: &Connection, gtfs_dir: &Path) -> Result<(), rusqlite::Error> {
-    //! Loads all the GTFS CSVs into `SQLite` tables in `db`
+pub fn load_gtfs(db: &Connection, gtfs_dir: &Path, shapes: bool) -> Result<(), rusqlite::Error> {
+    //! Loads all the GTFS CSVs into `SQLite` tables in `db`.
+    //! `shapes.txt` is big and only needed for route geometry, so it's only loaded if `shapes`.
     // let now = std::time::Instant::now();
 
     let mut dir: PathBuf = PathBuf::from(gtfs_dir);
@@ -146,6 +148,22 @@ pub fn load_gtfs(db: &Connection, gtfs_dir: &Path) -> Result<(), rusqlite::Error
     )?;
     db.execute_batch("CREATE INDEX idx_stoptimes ON StopTimes(trip_id, stop_id, stop_sequence)")?;
 
+    // shapes.txt is optional in GTFS; without it we fall back to straight lines between stops
+    db.execute_batch(
+        "CREATE TABLE Shapes (shape_id TEXT, shape_pt_lat REAL, shape_pt_lon REAL, shape_pt_sequence INT);",
+    )?;
+    dir.push("shapes.txt");
+    if shapes && dir.exists() {
+        db.execute_batch(&format!(
+            "CREATE VIRTUAL TABLE Shapes_VIRT USING csv(filename='{}', header=YES);
+            INSERT INTO Shapes (shape_id, shape_pt_lat, shape_pt_lon, shape_pt_sequence)
+            SELECT shape_id, shape_pt_lat, shape_pt_lon, shape_pt_sequence FROM Shapes_VIRT;",
+            dir.as_path().display()
+        ))?;
+    }
+    dir.pop();
+    db.execute_batch("CREATE INDEX idx_shapes ON Shapes(shape_id, shape_pt_sequence)")?;
+
     // eprintln!(
     //     "Info: GTFS actual tables (incl. StopTimes index) at {} ms.",
     //     now.elapsed().as_millis()
@@ -492,7 +510,54 @@ pub fn make_stop_sequence(
     topo_merge(mainde)
 }
 
-fn topo_merge(mut input: VecDeque<VecDeque<StopId>>) -> Result<Vec<StopId>> {
+pub fn merge_stop_sequences(
+    db: &Connection,
+    routes: &[String],
+    direction_name: &str,
+) -> anyhow::Result<Vec<StopId>> {
+    //! Creates a single ordered list of `stop_id`s for several routes in the same direction,
+    //! e.g. along a corridor, by merging each route's own stop sequence.
+    //! Routes which aren't in the GTFS are left out.
+    let mut sequences = VecDeque::new();
+    for route in routes {
+        match make_stop_sequence(db, route, direction_name) {
+            Ok(seq) => sequences.push_back(seq.into_iter().collect()),
+            Err(e) => {
+                warn!("{route} {direction_name} not in GTFS; leaving it out of the corridor\n{e}");
+            }
+        }
+    }
+    if sequences.is_empty() {
+        bail!("None of {} run {direction_name} in the GTFS", routes.join(", "));
+    }
+    topo_merge(sequences)
+}
+
+/// A row of a positions file
+#[derive(Deserialize, Debug)]
+#[allow(clippy::struct_field_names)] // the same as the GTFS columns
+struct Position {
+    /// ID of the stop
+    stop_id: StopId,
+    /// Name of the stop
+    stop_name: String,
+    /// Where the stop goes in the sequence; only the order matters
+    stop_sequence: f64,
+}
+
+pub fn load_positions(path: &Path) -> anyhow::Result<(Vec<StopId>, BTreeMap<StopId, String>)> {
+    //! Read a positions file: a CSV with `stop_id`, `stop_name` and `stop_sequence` columns.
+    //! Gives the stops in order of `stop_sequence`, and their names.
+    let mut rdr = csv::Reader::from_path(path)
+        .with_context(|| format!("Error opening positions file {}", path.display()))?;
+    let mut positions: Vec<Position> =
+        rdr.deserialize().collect::<Result<_, _>>().context("Error reading positions file")?;
+    positions.sort_by(|a, b| a.stop_sequence.total_cmp(&b.stop_sequence));
+    let names = positions.iter().map(|p| (p.stop_id, p.stop_name.clone())).collect();
+    Ok((positions.into_iter().map(|p| p.stop_id).collect(), names))
+}
+
+pub fn topo_merge(mut input: VecDeque<VecDeque<StopId>>) -> Result<Vec<StopId>> {
     //! Merge a collection of ordered sequences in a toposort-compatible way
 
     //! * input: a collection of sequences of of `stop_ids` (in `stop_sequence` order), one per `shape_id`  
@@ -560,7 +625,7 @@ fn topo_merge(mut input: VecDeque<VecDeque<StopId>>) -> Result<Vec<StopId>> {
     Ok(output)
 }
 
-fn gc_distance(from_lat: f64, from_lon: f64, to_lat: f64, to_lon: f64) -> f64 {
+pub fn gc_distance(from_lat: f64, from_lon: f64, to_lat: f64, to_lon: f64) -> f64 {
     //! Calculate the great-circle distance between two points on Earth.
     //! Takes coordinates in decimal degrees.
     #![allow(clippy::shadow_reuse)]
@@ -601,6 +666,149 @@ pub fn get_stop_names(
     Ok(output)
 }
 
+pub fn get_stop_coords(
+    db: &Connection,
+    input: &[StopId],
+) -> Result<BTreeMap<StopId, (f64, f64)>, serde_rusqlite::Error> {
+    //! Get stop (latitude, longitude) pairs from stop sequences
+    let mut output: BTreeMap<StopId, (f64, f64)> = BTreeMap::new();
+
+    let mut stmt = db.prepare_cached("SELECT stop_lat, stop_lon FROM Stops WHERE stop_id = :id")?;
+
+    for id in input {
+        let coords = stmt.query_row(&[(":id", &id)], |r| Ok((r.get(0)?, r.get(1)?)))?;
+        output.insert(*id, coords);
+    }
+
+    Ok(output)
+}
+
+/// The way from one stop to the next
+#[derive(Debug, Clone, PartialEq)]
+pub struct Leg {
+    /// Distance along the route, in metres
+    pub distance: f64,
+    /// (latitude, longitude) points from one stop to the next, inclusive
+    pub path: Vec<(f64, f64)>,
+}
+
+/// A GTFS shape's points, and the distance along it to each one
+struct Shape {
+    /// (latitude, longitude) in order
+    points: Vec<(f64, f64)>,
+    /// Cumulative distance to each point, in metres
+    along: Vec<f64>,
+}
+
+impl Shape {
+    fn nearest(&self, (lat, lon): (f64, f64), from: usize) -> Option<usize> {
+        //! Index of the point nearest to (`lat`, `lon`), at or after index `from`
+        self.points
+            .iter()
+            .enumerate()
+            .skip(from)
+            .map(|(i, p)| (i, gc_distance(lat, lon, p.0, p.1)))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(i, _)| i)
+    }
+}
+
+fn get_shape(db: &Connection, shape_id: &str) -> Result<Shape, rusqlite::Error> {
+    //! Load a shape's points in order
+    let mut stmt = db.prepare_cached(
+        "SELECT shape_pt_lat, shape_pt_lon FROM Shapes WHERE shape_id = :shape ORDER BY shape_pt_sequence",
+    )?;
+    let points: Vec<(f64, f64)> = stmt
+        .query_map(&[(":shape", &shape_id)], |r| Ok((r.get(0)?, r.get(1)?)))?
+        .collect::<Result<_, _>>()?;
+
+    let mut along = Vec::with_capacity(points.len());
+    let mut total = 0.0;
+    for (i, p) in points.iter().enumerate() {
+        if let Some(q) = i.checked_sub(1).and_then(|j| points.get(j)) {
+            total += gc_distance(q.0, q.1, p.0, p.1);
+        }
+        along.push(total);
+    }
+    Ok(Shape { points, along })
+}
+
+pub fn get_legs(
+    db: &Connection,
+    routes: &[String],
+    direction_name: &str,
+    stop_sequence: &[StopId],
+) -> Result<Vec<Leg>> {
+    //! The [`Leg`]s between consecutive stops of a (merged) stop sequence.
+    //! Each follows the shape of a variant of one of `routes` (the first, if it can)
+    //! which runs from one stop to the next, or else is a straight line if there's no such shape.
+    let direction = convert_direction(direction_name);
+    let coords = get_stop_coords(db, stop_sequence)?;
+
+    // the variant that gets from one stop to the other soonest, preferring the most frequent
+    let mut stmt = db.prepare_cached(
+        "SELECT A.shape_id FROM StopSeqs A, StopSeqs B
+        WHERE A.route_short_name IS :route AND A.direction_id IS :direction
+        AND B.shape_id = A.shape_id AND B.route_short_name IS :route AND B.direction_id IS :direction
+        AND A.stop_id = :from AND B.stop_id = :to AND B.stop_sequence > A.stop_sequence
+        ORDER BY B.stop_sequence - A.stop_sequence, A.qty DESC LIMIT 1;",
+    )?;
+    let mut shapes: BTreeMap<ShapeId, Shape> = BTreeMap::new();
+    // where the previous leg ended, so a leg on the same shape carries on from there
+    let mut resume: Option<(ShapeId, usize)> = None;
+
+    let mut legs = Vec::new();
+    for pair in stop_sequence.windows(2) {
+        let (from, to) = (coords[&pair[0]], coords[&pair[1]]);
+        let straight =
+            Leg { distance: gc_distance(from.0, from.1, to.0, to.1), path: vec![from, to] };
+
+        let mut shape_id: Option<ShapeId> = None;
+        for route in routes {
+            shape_id = stmt
+                .query_row(
+                    named_params! {":route": route, ":direction": direction, ":from": pair[0], ":to": pair[1]},
+                    |r| r.get(0),
+                )
+                .optional()?;
+            if shape_id.is_some() {
+                break;
+            }
+        }
+        let Some(shape_id) = shape_id else {
+            legs.push(straight);
+            resume = None;
+            continue;
+        };
+        if !shapes.contains_key(&shape_id) {
+            shapes.insert(shape_id.clone(), get_shape(db, &shape_id)?);
+        }
+        let shape = &shapes[&shape_id];
+
+        // snap each stop to its nearest point on the shape, going forwards
+        let start = match &resume {
+            Some((id, end)) if *id == shape_id => *end,
+            _ => 0,
+        };
+        let leg = shape.nearest(from, start).and_then(|a| Some((a, shape.nearest(to, a)?)));
+        legs.push(match leg {
+            Some((a, b)) if b > a => {
+                resume = Some((shape_id, b));
+                let mut path = vec![from];
+                path.extend_from_slice(&shape.points[a..=b]);
+                path.push(to);
+                path.dedup();
+                Leg { distance: shape.along[b] - shape.along[a], path }
+            }
+            _ => {
+                resume = None;
+                straight
+            }
+        });
+    }
+    Ok(legs)
+}
+
 pub fn get_service_count(
     db: &Connection,
     route: &str,
@@ -49,33 +49,184 @@ use simple_logger::SimpleLogger;
 use tempfile::{NamedTempFile, TempDir};
 use ureq::Agent;
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, VecDeque};
 use std::fmt::Write as FmtWrite;
 use std::io::{Cursor, Read, Write};
 use std::iter::Iterator;
 use std::path::{Path, PathBuf};
 
+mod geometry;
+
 mod gtfs;
-use crate::gtfs::{get_service_count, get_stop_names, load_gtfs, make_stop_sequence, StopId};
+use crate::gtfs::{
+    gc_distance, get_legs, get_service_count, get_stop_coords, get_stop_names, load_gtfs,
+    load_positions, make_stop_sequence, merge_stop_sequences, topo_merge, StopId,
+};
+
+mod colour;
+use crate::colour::{by_category, by_distance, by_stops, load_palette, ColourBy, Palette, Scheme};
+
+mod diff;
+use crate::diff::diff_one;
+
+mod export;
+use crate::export::{geojson, od_rows, stop_rows, to_csv, to_json};
+
+mod map;
+use crate::map::map_one;
+
+mod metrics;
+use crate::metrics::{
+    load_capacities, peak_segment, trip_lengths, LengthBinRow, LoadFactorRow, TripLengthRow,
+    TripLengths,
+};
+
+mod render;
+use crate::render::{render, Format};
+
+mod report;
+use crate::report::{stop_totals, stops_html};
+
+mod timeseries;
+use crate::timeseries::{animate_one, trend_rows, trends_html, Frame, History};
 
 mod visualise;
-use crate::visualise::visualise_one;
+use crate::visualise::{
+    segment_loads, visualise_one, DrawOptions, Focus, Layout, Provenance, Scale, Spacing,
+    Thresholds,
+};
 use std::fs::File;
 
 /// A (route, direction) pair
 type RouteDir = (String, String);
 
+/// Kinds of diagram
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum View {
+    /// Arcs between stops, which are laid out in a line
+    Arcs,
+    /// Stops where they really are, joined by lines as wide as the load between them
+    Map,
+}
+
+/// Tables which can be exported alongside the diagrams
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Export {
+    /// Passenger-km and mean and median trip length for each route, and trip length distributions, as CSV
+    TripLengths,
+    /// The load between each pair of stops, and the mean load factor per service if capacity is known, as CSV
+    LoadFactors,
+    /// Each route's origin-destination matrix, in long form, as CSV and JSON (and Parquet, if built with it)
+    Od,
+    /// Each route's stops with their coordinates, boardings, alightings and departing load, as CSV
+    StopLoads,
+    /// Each route's stops and the segments between them, with their loads, as `GeoJSON`
+    Geojson,
+}
+
+/// Reports over the whole network, written instead of diagrams
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Report {
+    /// Boardings and alightings at each stop over all routes, busiest first, as CSV and HTML
+    Stops,
+}
+
 /// The options struct for the CLI.
 #[allow(clippy::struct_excessive_bools)]
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Opts {
-    /// Colour by destination instead of by origin
-    #[arg(short = 's', long = "swap-colours")]
+    /// Colour by destination instead of by origin (same as `--colour-by destination`)
+    #[arg(short = 's', long = "swap-colours", conflicts_with = "colour_by")]
     swap: bool,
+    /// Kind of diagram to draw
+    #[arg(long = "view", value_enum, default_value_t = View::Arcs)]
+    view: View,
+    /// Output format for the diagrams
+    #[arg(short = 'f', long = "format", value_enum, default_value_t = Format::Svg)]
+    format: Format,
+    /// Resolution for PNG and PDF output, in dots per inch
+    #[arg(long = "dpi", default_value_t = 96.0)]
+    dpi: f32,
+    /// Scale PNG and PDF output down to at most this many pixels wide (at --dpi)
+    #[arg(long = "max-width", value_names(&["pixels"]))]
+    max_width: Option<u32>,
+    /// Arc width scale: global (network-wide), per-route, or fixed:N (N passengers per pixel)
+    #[arg(long = "scale", default_value = "per-route", value_names(&["scale"]))]
+    scale: Scale,
+    /// Explain how to read the diagrams, and add a footer saying where the data came from
+    #[arg(long = "legend")]
+    legend: bool,
+    /// Add passenger-km and mean and median trip lengths along the route to the subtitle
+    #[arg(long = "trip-lengths")]
+    trip_lengths: bool,
+    /// Fade out all arcs except trips to or from this stop
+    #[arg(long = "focus-stop", value_names(&["stop_id"]), conflicts_with = "focus_segment")]
+    focus_stop: Option<StopId>,
+    /// Fade out all arcs except trips on board all the way between these two stops
+    #[arg(long = "focus-segment", value_names(&["from", "to"]), num_args = 2)]
+    focus_segment: Vec<StopId>,
+    /// Passengers each service can carry, for load factors
+    #[arg(long = "capacity", value_names(&["passengers"]))]
+    capacity: Option<Quantity>,
+    /// A CSV of route,capacity (with a header row) giving capacity per route, overriding --capacity.
+    /// A corridor's capacity is its routes' capacities, averaged over their services
+    #[arg(long = "capacities", value_names(&["path"]))]
+    capacities: Option<PathBuf>,
+    /// Write a network-wide report instead of diagrams
+    #[arg(long = "report", value_enum, conflicts_with = "one")]
+    report: Option<Report>,
+    /// Draw the change from this earlier patronage CSV (path/URI) to `in_file`, for the --one route/direction
+    #[arg(long = "diff", value_names(&["path"]), requires = "one", conflicts_with_all = ["batch", "report", "corridor", "positions"])]
+    diff: Option<PathBuf>,
+    /// GTFS directory (path/URI) for the earlier month of --diff [default: --gtfs]
+    #[arg(long = "diff-gtfs", value_names(&["path"]), requires = "diff")]
+    diff_gtfs: Option<PathBuf>,
+    /// Draw these routes together as one corridor, over all of their stops
+    #[arg(long = "corridor", value_names(&["route"]), value_delimiter = ',', conflicts_with_all = ["one", "report"], requires = "corridor_direction")]
+    corridor: Vec<String>,
+    /// Direction of the corridor (with --corridor or --positions)
+    #[arg(long = "corridor-direction", value_names(&["direction"]))]
+    corridor_direction: Option<String>,
+    /// Also write these tables next to the diagrams: CSV, except for `od` (CSV, JSON, and Parquet if built with it) and `geojson` (`GeoJSON`)
+    #[arg(long = "export", value_enum, value_delimiter = ',')]
+    export: Vec<Export>,
+    /// Diagram geometry
+    #[command(flatten)]
+    layout: Layout,
+    /// Merge origin-destination pairs with fewer passengers than this into an "other" band
+    #[arg(long = "min-flow", value_names(&["passengers"]), default_value_t = 0)]
+    min_flow: Quantity,
+    /// Draw only the largest K destinations from each origin, merging the rest into "other"
+    #[arg(long = "top-k", value_names(&["K"]))]
+    top_k: Option<usize>,
+    /// What to colour the arcs by
+    #[arg(long = "colour-by", value_enum, default_value_t = ColourBy::Origin)]
+    colour_by: ColourBy,
     /// Colour neighbours differently rather than similarly
     #[arg(short = 'j', long = "jumble-colours")]
     jumble: bool,
+    /// Seed for the colour palette; the same seed always gives the same colours
+    #[arg(long = "seed", default_value_t = 0)]
+    seed: u64,
+    /// Colour scheme for the arcs
+    #[arg(long = "scheme", value_enum, default_value_t = Scheme::Hues)]
+    scheme: Scheme,
+    /// Path to a file of colours to use instead of a scheme, one per line
+    #[arg(long = "palette", value_names(&["path"]), conflicts_with = "scheme")]
+    palette: Option<PathBuf>,
+    /// Hue of the first stop, in degrees (hues scheme)
+    #[arg(long = "hue-start", default_value_t = 0.0)]
+    hue_start: f64,
+    /// Hue that the last stop approaches, in degrees (hues scheme)
+    #[arg(long = "hue-stop", default_value_t = 360.0)]
+    hue_stop: f64,
+    /// `HSLuv` saturation from 0 to 100 (hues scheme)
+    #[arg(long = "chroma", default_value_t = 100.0)]
+    chroma: f64,
+    /// `HSLuv` lightness from 0 to 100 (hues and mono schemes)
+    #[arg(long = "lightness", default_value_t = 55.0)]
+    lightness: f64,
     /// List all route/direction pairs and exit
     #[arg(short = 'l', long = "list")]
     list: bool,
@@ -85,7 +236,7 @@ struct Opts {
     /// Tell me more (or less)
     #[clap(flatten)]
     verbose: Verbosity<clap_verbosity_flag::InfoLevel>,
-    /// Get all utility scripts at https://github.com/alexjago/fluvial/tree/master/utils
+    /// Get the scripts for preparing data (crosstabs, route counts, rail positions) at <https://github.com/alexjago/fluvial/tree/master/utils>
     #[arg(short = 'U', long = "utilities")]
     utilities: bool,
     #[arg(long = "ftime")]
@@ -103,11 +254,22 @@ struct Opts {
     #[arg(short = 'b', long = "batch", conflicts_with = "license")]
     /// Treat in_file as a batch CSV of <patronage zip URL>, <gtfs zip URL>; conflicts with --gtfs
     batch: bool,
+    /// With --batch, also draw each route/direction over all the months as an animated SVG
+    #[arg(long = "animate", requires = "batch")]
+    animate: bool,
+    /// With --batch, also write each route/direction's boardings, services and peak load by month,
+    /// as a CSV and a page of sparklines
+    #[arg(long = "trends", requires = "batch")]
+    trends: bool,
+    /// How long each month is shown for in animations
+    #[arg(long = "frame-seconds", value_names(&["seconds"]), default_value_t = 1.5)]
+    frame_seconds: f64,
     /// A directory/URI of GTFS files to determine stop names and sequences from
-    #[arg(short = 'g', long = "gtfs", value_names(&["path"]), required_unless_present_any = &["batch", "license", "positions", "utilities"], conflicts_with_all = ["batch", "positions"])]
+    #[arg(short = 'g', long = "gtfs", value_names(&["path"]), required_unless_present_any = &["batch", "license", "utilities"], conflicts_with = "batch")]
     gtfs_dir: Option<PathBuf>,
-    /// A positions file to determine stop names and sequences from. Currently does nothing.
-    #[arg(short = 'p', long = "positions", value_names(&["path"]), required_unless_present_any = &["batch", "license", "gtfs_dir", "utilities"], conflicts_with_all = ["batch", "gtfs_dir"])]
+    /// A CSV of `stop_id,stop_name,stop_sequence` giving the stops (and names) of a corridor, in order.
+    /// Draws every route in --corridor-direction (or just those in --corridor) along it.
+    #[arg(short = 'p', long = "positions", value_names(&["path"]), conflicts_with_all = ["one", "report"], requires = "corridor_direction")]
     positions: Option<PathBuf>,
     /// The path/URI of the patronage CSV (or path to batch file, with --batch)
     // #[arg(required_unless_one = &["license", "utilities"])]
@@ -116,6 +278,17 @@ struct Opts {
     out_dir: Option<PathBuf>,
 }
 
+impl Opts {
+    /// What to colour the arcs by, taking `--swap-colours` into account
+    const fn colour_by(&self) -> ColourBy {
+        if self.swap {
+            ColourBy::Destination
+        } else {
+            self.colour_by
+        }
+    }
+}
+
 fn list_routes(db: &Connection) -> Result<Vec<RouteDir>> {
     //! List all the route/direction combinations
     let mut rdstmt = db.prepare("SELECT DISTINCT route, direction FROM Patronage;")?;
@@ -129,31 +302,42 @@ fn list_routes(db: &Connection) -> Result<Vec<RouteDir>> {
 
     Ok(rd)
 }
-#[inline(never)]
-fn make_one(
-    db: &Connection,
-    route: &str,
-    direction: &str,
-    ftime: &Option<String>,
-) -> Result<BTreeMap<(StopId, StopId), Quantity>> {
-    //! Get a mapping of {(origin, destination) : patronage} for a **single** route/direction pair.
+fn ftime_filter(ftime: Option<&str>) -> (&'static str, String) {
+    //! A WHERE clause fragment, and the value for its `:time` parameter, to filter by `--ftime`
 
     // The time filtering is a bit wacky. Something like `time IS *` in a WHERE clause isn't allowed
     // but it *is* OK to do `((time IS ...) OR (1=1))`. The rest of the madness is just to
     // ensure that we always pass one parameter for :time and to handle --ftime NULL
 
-    let ftime_ins = match *ftime {
-        Some(_) => String::from("AND (time IS :time)"),
-        None => String::from("AND ((time IS :time) OR (1=1))"),
+    let ftime_ins = match ftime {
+        Some(_) => "AND (time IS :time)",
+        None => "AND ((time IS :time) OR (1=1))",
     };
 
-    let ftime_sub = match ftime.as_ref() {
-        Some(s) => s.clone(),
-        None => String::from("NULL"),
-    };
+    let ftime_sub = ftime.map_or_else(|| String::from("NULL"), String::from);
+
+    (ftime_ins, ftime_sub)
+}
+
+fn routes_param(routes: &[String]) -> Result<String> {
+    //! A value for a `:routes` parameter, used as `route IN (SELECT value FROM json_each(:routes))`
+    Ok(serde_json::to_string(routes)?)
+}
+
+#[inline(never)]
+fn make_one(
+    db: &Connection,
+    routes: &[String],
+    direction: &str,
+    ftime: &Option<String>,
+) -> Result<BTreeMap<(StopId, StopId), Quantity>> {
+    //! Get a mapping of {(origin, destination) : patronage} for a **single** route/direction pair
+    //! (or several routes in the same direction, all together).
+
+    let (ftime_ins, ftime_sub) = ftime_filter(ftime.as_deref());
 
     let stmt_txt = format!("SELECT origin_stop, destination_stop, sum(quantity)
-        FROM Patronage WHERE route IS :route AND direction IS :direction {} GROUP BY origin_stop, destination_stop;", ftime_ins);
+        FROM Patronage WHERE route IN (SELECT value FROM json_each(:routes)) AND direction IS :direction {} GROUP BY origin_stop, destination_stop;", ftime_ins);
 
     let mut stmt = db.prepare(&stmt_txt).context("Failed preparing statement.")?;
 
@@ -161,7 +345,7 @@ fn make_one(
 
     stmt.query_map(
         named_params! {
-            ":route": &route,
+            ":routes": &routes_param(routes)?,
             ":direction": &direction,
             ":time": &ftime_sub.as_str(),
         },
@@ -175,6 +359,73 @@ fn make_one(
     Ok(tree)
 }
 
+fn make_one_categories(
+    db: &Connection,
+    routes: &[String],
+    direction: &str,
+    ftime: Option<&str>,
+    column: &str,
+) -> Result<BTreeMap<(StopId, StopId), String>> {
+    //! Get a mapping of {(origin, destination) : most common value of `column`}
+    //! for a **single** route/direction pair (or several routes, all together).
+    //! `column` is e.g. `ticket_type`, `time` or `route`.
+
+    let (ftime_ins, ftime_sub) = ftime_filter(ftime);
+
+    // SQLite takes the bare column from the row which satisfies MAX()
+    let stmt_txt = format!(
+        "SELECT origin_stop, destination_stop, category, MAX(qty) FROM
+        (SELECT origin_stop, destination_stop, {column} AS category, sum(quantity) AS qty
+        FROM Patronage WHERE route IN (SELECT value FROM json_each(:routes)) AND direction IS :direction {ftime_ins}
+        GROUP BY origin_stop, destination_stop, {column})
+        GROUP BY origin_stop, destination_stop;"
+    );
+
+    let mut stmt = db.prepare(&stmt_txt).context("Failed preparing statement.")?;
+
+    let tree = stmt
+        .query_map(
+            named_params! {
+                ":routes": &routes_param(routes)?,
+                ":direction": &direction,
+                ":time": &ftime_sub.as_str(),
+            },
+            |row| Ok(((row.get(0)?, row.get(1)?), row.get(2)?)),
+        )?
+        .filter_map(core::result::Result::ok)
+        .collect();
+
+    Ok(tree)
+}
+
+fn get_network_max(db: &Connection, ftime: Option<&str>) -> Result<Quantity> {
+    //! Get the largest (boardings + alightings) at a single stop, for any route/direction,
+    //! in the same way that each route's diagram is scaled.
+
+    let (ftime_ins, ftime_sub) = ftime_filter(ftime);
+
+    let stmt_txt = format!(
+        "SELECT MAX(B.bmax + A.amax) FROM
+        (SELECT route, direction, MAX(qty) AS bmax FROM
+            (SELECT route, direction, sum(quantity) AS qty FROM Patronage WHERE 1=1 {ftime_ins}
+            GROUP BY route, direction, origin_stop)
+        GROUP BY route, direction) B
+        INNER JOIN
+        (SELECT route, direction, MAX(qty) AS amax FROM
+            (SELECT route, direction, sum(quantity) AS qty FROM Patronage WHERE 1=1 {ftime_ins}
+            GROUP BY route, direction, destination_stop)
+        GROUP BY route, direction) A
+        ON A.route = B.route AND A.direction = B.direction;"
+    );
+
+    let mut stmt = db.prepare(&stmt_txt).context("Failed preparing statement.")?;
+
+    let max: Option<Quantity> =
+        stmt.query_row(named_params! {":time": &ftime_sub.as_str()}, |row| row.get(0))?;
+
+    Ok(max.unwrap_or(0))
+}
+
 #[inline(never)]
 fn get_boardings(
     // used in gtfs.rs for stop sequencing
@@ -228,6 +479,7 @@ fn main() -> Result<()> {
     SimpleLogger::new().with_level(opts.verbose.log_level_filter()).init()?;
 
     trace!("{:#?}", opts);
+    opts.layout.check()?;
 
     if opts.license {
         println!("Fluvial, a transit patronage visualiser.");
@@ -235,80 +487,141 @@ fn main() -> Result<()> {
         println!("{}", include_str!("gpl_notice.txt"));
         println!("\nAll components, their authors, source code repositories, and license details are listed below:\n");
         println!("{}", include_str!("dependencies.txt"));
+        println!("\n{}", include_str!("fonts/LICENSE.txt"));
         /* Before releasing a new version, run...
-           cargo-license --avoid-build-deps --avoid-dev-deps -a -t > src/dependencies.txt
+           cargo-license --avoid-build-deps --avoid-dev-deps --all-features -a -t > src/dependencies.txt
         */
         return Ok(());
     }
 
     if opts.utilities {
-        println!("Get all utility scripts for Fluvial at\nhttps://github.com/alexjago/fluvial/tree/master/utils");
+        println!("Get Fluvial's scripts for preparing data at\nhttps://github.com/alexjago/fluvial/tree/master/utils");
         return Ok(());
     }
 
+    let palette = Palette {
+        scheme: opts.scheme,
+        start: opts.hue_start,
+        stop: opts.hue_stop,
+        chroma: opts.chroma,
+        lightness: opts.lightness,
+        seed: opts.seed,
+        jumble: opts.jumble,
+        custom: opts.palette.as_deref().map(load_palette).transpose()?,
+    };
+
     if opts.batch {
         // if path is "-" then it's std input
         // thanks /u/burntsushi
         // https://www.reddit.com/r/rust/comments/jv3q3e/how_to_select_between_reading_from_a_file_and/gci1mww/
-        let path = opts.in_file.context("Please specify a file path '-' for standard input")?;
+        let path =
+            opts.in_file.as_ref().context("Please specify a file path '-' for standard input")?;
 
         let batch_stream: Box<dyn std::io::Read + 'static> = if path.as_os_str() == "-" {
             Box::new(std::io::stdin())
         } else {
-            Box::new(std::fs::File::open(&path).context("Error opening batch file for reading")?)
+            Box::new(std::fs::File::open(path).context("Error opening batch file for reading")?)
         };
 
         let mut rdr = csv::ReaderBuilder::new().has_headers(false).from_reader(batch_stream);
 
+        // every month of every route, for animations and trends
+        let mut history = History::new();
+        let mut keep = (opts.animate || opts.trends).then_some(&mut history);
+
         for r in rdr.records().filter_map(std::result::Result::ok) {
             let patronage_uri = PathBuf::from(r.get(0).context("No patronage URI!")?);
             let gtfs_uri = PathBuf::from(r.get(1).context("No GTFS URI!")?);
             if let Err(e) = single_month(
                 &Some(patronage_uri),
-                opts.list,
                 &Some(gtfs_uri),
-                &opts.out_dir,
-                &opts.one,
-                &opts.ftime,
-                opts.swap,
-                opts.jumble,
-                &opts.css,
+                &opts,
+                &palette,
+                keep.as_deref_mut(),
             ) {
                 error!("Skipping this month: {e}");
             }
         }
+
+        let out_dir = match opts.out_dir.as_ref() {
+            Some(o) => o.clone(),
+            None => std::env::current_dir()?,
+        };
+        write_history(&history, &opts, &palette, &out_dir)?;
     } else {
         // No CSV to iterate over or anything like that, just go
-        single_month(
-            &opts.in_file,
-            opts.list,
-            &opts.gtfs_dir,
-            &opts.out_dir,
-            &opts.one,
-            &opts.ftime,
-            opts.swap,
-            opts.jumble,
-            &opts.css,
-        )?;
+        single_month(&opts.in_file, &opts.gtfs_dir, &opts, &palette, None)?;
+    }
+    Ok(())
+}
+
+fn write_history(history: &History, opts: &Opts, palette: &Palette, out_dir: &Path) -> Result<()> {
+    //! Write out whatever a batch asked for across its months: animations and trends
+    if opts.animate {
+        let mut completed = 0_usize;
+        for ((route, direction), frames) in history {
+            let animated = animate_one(
+                frames,
+                route,
+                direction,
+                opts.colour_by(),
+                palette,
+                opts.css.as_ref(),
+                &opts.layout,
+                opts.frame_seconds,
+            )
+            .context("Error generating SVG")
+            .and_then(|out| {
+                write_batch_outfile(
+                    out_dir,
+                    "animated",
+                    &format!("{route}_{direction}.svg"),
+                    opts.ftime.as_deref(),
+                    out,
+                )
+                .context("Error writing animation")
+            });
+            match animated {
+                Ok(()) => completed += 1,
+                Err(e) => error!("Skipping the animation of {route} {direction}: {e:#}"),
+            }
+        }
+        info!("{completed} of {} animations", history.len());
+    }
+    if opts.trends {
+        let rows = trend_rows(history);
+        let title = format!(
+            "Route trends{}",
+            opts.ftime.as_ref().map_or_else(String::new, |t| format!(" ({t})"))
+        );
+        write_batch_outfile(out_dir, "trends", "trends.csv", opts.ftime.as_deref(), to_csv(&rows)?)
+            .context("Error writing trends")?;
+        write_batch_outfile(
+            out_dir,
+            "trends",
+            "trends.html",
+            opts.ftime.as_deref(),
+            trends_html(&rows, history, &title)?,
+        )
+        .context("Error writing trends")?;
+        info!("Trends for {} routes", history.len());
     }
     Ok(())
 }
 
-#[allow(clippy::fn_params_excessive_bools)]
-#[allow(clippy::too_many_arguments)]
 #[allow(clippy::too_many_lines)]
 fn single_month(
     in_file: &Option<PathBuf>,
-    list: bool,
     gtfs_dir: &Option<PathBuf>,
-    out_dir: &Option<PathBuf>,
-    one: &[String],
-    ftime: &Option<String>,
-    swap: bool,
-    jumble: bool,
-    css: &Option<PathBuf>,
+    opts: &Opts,
+    palette: &Palette,
+    mut history: Option<&mut History>,
 ) -> Result<()> {
     //! Run a single month's worth of processing.
+    //! `in_file` and `gtfs_dir` are given separately as they vary in batch mode.
+    //! Each route drawn is added to any `history`, for animations and trends across months.
+    let Opts { list, out_dir, one, ftime, css, .. } = opts;
+    let colour_by = opts.colour_by();
     let db = Connection::open_in_memory().context("Could not open virtual database")?;
     rusqlite::vtab::csvtab::load_module(&db)
         .context("Could not load CSV module of virtual database")?;
@@ -341,7 +654,7 @@ fn single_month(
 
     debug!("Loaded patronage CSV");
 
-    if list {
+    if *list {
         match list_routes(&db) {
             Ok(l) => {
                 for k in &l {
@@ -376,7 +689,14 @@ fn single_month(
             None => gtfs_dir.as_ref().context("Missing GTFS directory")?.as_path(),
         };
 
-        match load_gtfs(&db, gtfs_actual_dir) {
+        // route geometry is only worth loading if something will be drawn or measured along it
+        let measure = opts.trip_lengths || opts.export.contains(&Export::TripLengths);
+        let geometry = measure
+            || opts.layout.spacing == Spacing::Distance
+            || opts.view == View::Map
+            || opts.export.contains(&Export::Geojson);
+
+        match load_gtfs(&db, gtfs_actual_dir, geometry) {
             Ok(_) => {
                 info!("Successfully loaded GTFS data as a database.",);
             }
@@ -385,8 +705,6 @@ fn single_month(
             }
         }
 
-        // TODO: load positions file into db?
-
         // Output Directory
         #[allow(clippy::shadow_reuse)]
         let out_dir = match out_dir.as_ref() {
@@ -396,17 +714,202 @@ fn single_month(
 
         // Month and Year
         let (month, year) = get_month_year(&db)?;
+
+        if opts.report == Some(Report::Stops) {
+            let totals = stop_totals(&db, ftime.as_deref()).context("Error totalling stops")?;
+            let title = format!(
+                "Stop patronage – {} {}{}",
+                convert_monthname(&month),
+                year,
+                ftime.as_ref().map_or_else(String::new, |t| format!(" ({t})"))
+            );
+            write_outfile(&out_dir, "stops.csv", &month, &year, ftime, to_csv(&totals)?)
+                .context("Error writing stops report")?;
+            write_outfile(
+                &out_dir,
+                "stops.html",
+                &month,
+                &year,
+                ftime,
+                stops_html(&totals, &title)?,
+            )
+            .context("Error writing stops report")?;
+            info!("{} stops reported", totals.len());
+            return Ok(());
+        }
+
+        if let Some(before_file) = &opts.diff {
+            let (route, direction) = (&one[0], &one[1]);
+            let before_db =
+                Connection::open_in_memory().context("Could not open virtual database")?;
+            rusqlite::vtab::csvtab::load_module(&before_db)
+                .context("Could not load CSV module of virtual database")?;
+            let before_tmpfile = if before_file.exists() {
+                None
+            } else {
+                Some(download_patronage(&dl_agent, before_file)?)
+            };
+            let before_filename =
+                PathBuf::from(before_tmpfile.as_ref().map_or(before_file.as_path(), |t| t.path()));
+            load_patronage(&before_db, &before_filename, before_tmpfile)?;
+            let (before_month, before_year) = get_month_year(&before_db)?;
+
+            let before = make_one(&before_db, std::slice::from_ref(route), direction, ftime)
+                .context("Error collating earlier stop patronage")?;
+            let after = make_one(&db, std::slice::from_ref(route), direction, ftime)
+                .context("Error collating stop patronage")?;
+            let stop_seq = make_stop_sequence(&db, route, direction).with_context(|| {
+                format!("Error making stop sequences. Does {route} {direction} exist?")
+            })?;
+            let mut stop_names = get_stop_names(&db, &stop_seq)?;
+
+            // the earlier month may have run another way; draw both on all of their stops
+            let stop_seq = if let Some(before_gtfs) = &opts.diff_gtfs {
+                let before_tempdir = if before_gtfs.exists() {
+                    None
+                } else {
+                    Some(download_gtfs(&dl_agent, before_gtfs)?)
+                };
+                let before_gtfs =
+                    before_tempdir.as_ref().map_or(before_gtfs.as_path(), |t| t.path());
+                load_gtfs(&before_db, before_gtfs, false)
+                    .context("Failed to load earlier GTFS from disk.")?;
+                let before_seq =
+                    make_stop_sequence(&before_db, route, direction).with_context(|| {
+                        format!("Error making earlier stop sequences for {route} {direction}")
+                    })?;
+                for (stop, name) in get_stop_names(&before_db, &before_seq)? {
+                    stop_names.entry(stop).or_insert(name);
+                }
+                topo_merge(VecDeque::from([
+                    before_seq.into_iter().collect(),
+                    stop_seq.into_iter().collect(),
+                ]))?
+            } else {
+                stop_seq
+            };
+            let placed =
+                |(from, to): &&(StopId, StopId)| stop_seq.contains(from) && stop_seq.contains(to);
+            let unplaced: Quantity =
+                before.iter().chain(after.iter()).filter(|(k, _)| !placed(k)).map(|(_, v)| v).sum();
+            if unplaced > 0 {
+                warn!("{unplaced} passengers between stops off {route} {direction} are left out of the diff");
+            }
+
+            // distance spacing needs every stop in the later GTFS, to measure between them
+            let distances = if opts.layout.spacing == Spacing::Distance {
+                let coords = get_stop_coords(&db, &stop_seq)?;
+                if stop_seq.iter().all(|stop| coords.contains_key(stop)) {
+                    let legs = get_legs(&db, std::slice::from_ref(route), direction, &stop_seq)?;
+                    Some(legs.iter().map(|leg| leg.distance).collect::<Vec<f64>>())
+                } else {
+                    warn!("Some of the earlier month's stops aren't in the GTFS; spacing stops evenly");
+                    None
+                }
+            } else {
+                None
+            };
+
+            let out = diff_one(
+                &before,
+                &after,
+                &stop_seq,
+                &stop_names,
+                route,
+                direction,
+                &format!("{} {}", convert_monthname(&before_month), before_year),
+                &format!("{} {}", convert_monthname(&month), year),
+                css.as_ref(),
+                &opts.layout,
+                distances.as_deref(),
+            )
+            .context("Error generating SVG")?;
+            let out = render(&out, opts.format, opts.dpi, opts.max_width)
+                .context("Error rendering diagram")?;
+            write_outfile(
+                &out_dir,
+                &format!("{route}_{direction}_diff.{}", opts.format.extension()),
+                &month,
+                &year,
+                ftime,
+                &out,
+            )
+            .context("Error writing diagram file")?;
+            return Ok(());
+        }
         let mut rd_seq: Vec<RouteDir> = Vec::with_capacity(1);
 
         // {route : [directions]}
         let mut rd_tree: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        // for --export trip-lengths
+        let mut all_trips: Vec<(RouteDir, Quantity, TripLengths)> = Vec::new();
+        // for --export load-factors
+        let mut load_rows: Vec<LoadFactorRow> = Vec::new();
+
+        let capacities = match &opts.capacities {
+            Some(path) => load_capacities(path)?,
+            None => BTreeMap::new(),
+        };
 
-        if one.len() == 2 {
+        // a corridor is drawn like a single route, named after its routes (or positions file)
+        let positions = opts.positions.as_deref().map(load_positions).transpose()?;
+        let mut corridor: Option<Vec<String>> = None;
+
+        if let Some(direction) = &opts.corridor_direction {
+            let routes = if opts.corridor.is_empty() {
+                list_routes(&db)
+                    .context("Failed to list routes")?
+                    .into_iter()
+                    .filter_map(|(r, d)| (d == *direction).then_some(r))
+                    .collect()
+            } else {
+                opts.corridor.clone()
+            };
+            let name = match &opts.positions {
+                Some(p) if opts.corridor.is_empty() => {
+                    p.file_stem().map_or_else(|| "corridor".into(), |s| s.to_string_lossy().into())
+                }
+                _ => routes.join("+"),
+            };
+            rd_seq.push((name, direction.clone()));
+            corridor = Some(routes);
+        } else if one.len() == 2 {
             rd_seq.push((one[0].clone(), one[1].clone()));
         } else {
             rd_seq = list_routes(&db).context("Failed to list routes")?;
         }
-        // TODO: take input from positions file here?
+
+        let provenance = opts.legend.then(|| {
+            let source = |p: &Option<PathBuf>| p.as_ref().map(|p| p.display().to_string());
+            let mut filters = Vec::new();
+            if let Some(t) = ftime {
+                filters.push(format!("time of day {t}"));
+            }
+            if opts.min_flow > 0 {
+                filters.push(format!("min flow {}", opts.min_flow));
+            }
+            if let Some(k) = opts.top_k {
+                filters.push(format!("top {k} destinations"));
+            }
+            Provenance {
+                patronage: source(in_file).unwrap_or_default(),
+                gtfs: source(gtfs_dir).or_else(|| source(&opts.positions)).unwrap_or_default(),
+                filters,
+            }
+        });
+
+        let focus = match (opts.focus_stop, opts.focus_segment.as_slice()) {
+            (Some(stop), _) => Some(Focus::Stop(stop)),
+            (None, [from, to]) => Some(Focus::Segment(*from, *to)),
+            _ => None,
+        };
+
+        let network_max = match opts.scale {
+            Scale::Global => {
+                get_network_max(&db, ftime.as_deref()).context("Error finding network maximum")?
+            }
+            _ => 0,
+        };
 
         //eprintln!("rds: {:?}", rds);
         let mut completed = 0_usize;
@@ -416,12 +919,22 @@ fn single_month(
         for (route, direction) in rd_seq.iter().progress() {
             trace!("{} {}", route, direction);
 
-            let patronages =
-                make_one(&db, route, direction, ftime).context("Error collating stop patronage")?;
+            let routes = corridor.clone().unwrap_or_else(|| vec![route.clone()]);
+
+            let mut patronages = make_one(&db, &routes, direction, ftime)
+                .context("Error collating stop patronage")?;
 
-            let stop_seq: Vec<StopId> = match make_stop_sequence(&db, route, direction) {
+            let stop_seq = match (&positions, &corridor) {
+                (Some((seq, _)), _) => Ok(seq.clone()),
+                (None, Some(_)) => merge_stop_sequences(&db, &routes, direction),
+                (None, None) => make_stop_sequence(&db, route, direction),
+            };
+            let stop_seq: Vec<StopId> = match stop_seq {
                 Ok(o) => o,
                 Err(e) => {
+                    if corridor.is_some() {
+                        return Err(e).context("Error making the corridor's stop sequence");
+                    }
                     if one.len() == 2 {
                         bail!(
                             "Error making stop sequences. Does {} {} exist? Perhaps it is seasonal and therefore not in the current GTFS data... try transitfeeds.com to see if they have a historical version.\n{}",
@@ -435,48 +948,308 @@ fn single_month(
                 }
             };
 
-            let stop_names = get_stop_names(&db, &stop_seq)?;
-            // TODO: take info from positions file here?
+            let mut stop_names = get_stop_names(&db, &stop_seq)?;
+            if let Some((_, names)) = &positions {
+                stop_names.extend(names.iter().map(|(k, v)| (*k, v.clone())));
+            }
 
-            let service_count = get_service_count(&db, route, direction, &month, &year)?;
-            // TODO: take info from positions file here? How?
+            // a corridor's capacity is its routes' capacities, weighted by their services
+            let mut service_count = 0;
+            let mut capacity_sum = Some(0);
+            for r in &routes {
+                let services = get_service_count(&db, r, direction, &month, &year)?;
+                service_count += services;
+                capacity_sum = capacity_sum
+                    .zip(capacities.get(r).copied().or(opts.capacity))
+                    .map(|(sum, c)| sum + services * c);
+            }
 
-            let out = visualise_one(
-                &patronages,
-                &stop_seq,
-                &stop_names,
-                service_count,
-                route,
-                direction,
-                ftime,
-                convert_monthname(&month),
-                &year,
-                swap,
-                jumble,
-                css,
-            )
+            if corridor.is_some() {
+                // trips to or from off the corridor can't be placed on it
+                patronages.retain(|(from, to), _| stop_seq.contains(from) && stop_seq.contains(to));
+            }
+
+            // each arc's most common category, if colouring by one
+            let column = match colour_by {
+                ColourBy::Ticket => Some("ticket_type"),
+                ColourBy::Time => Some("time"),
+                ColourBy::Route => Some("route"),
+                _ => None,
+            };
+            let categories = match column {
+                Some(c) => make_one_categories(&db, &routes, direction, ftime.as_deref(), c)?,
+                None => BTreeMap::new(),
+            };
+            // where the stops are, for maps, distance spacing and colours, and trip lengths
+            let coords = if geometry
+                || colour_by == ColourBy::Distance
+                || opts.export.contains(&Export::StopLoads)
+            {
+                get_stop_coords(&db, &stop_seq)?
+            } else {
+                BTreeMap::new()
+            };
+
+            let arc_classes = match colour_by {
+                ColourBy::Origin | ColourBy::Destination => None,
+                ColourBy::Stops => Some(by_stops(&patronages, &stop_seq, palette)),
+                ColourBy::Distance => Some(by_distance(&patronages, &coords, palette)),
+                ColourBy::Ticket | ColourBy::Time | ColourBy::Route => {
+                    Some(by_category(&categories, palette))
+                }
+            };
+
+            // the way between stops, for maps, distance spacing and trip lengths
+            let legs =
+                if geometry { get_legs(&db, &routes, direction, &stop_seq)? } else { Vec::new() };
+            let distances: Vec<f64> = legs.iter().map(|leg| leg.distance).collect();
+            let trips = measure.then(|| {
+                // (trips which go around the loop go straight from the last stop to the first)
+                let loop_distance = match (stop_seq.first(), stop_seq.last()) {
+                    (Some(first), Some(last)) => {
+                        let (a, b) = (coords[first], coords[last]);
+                        gc_distance(b.0, b.1, a.0, a.1)
+                    }
+                    _ => 0.0,
+                };
+                trip_lengths(&patronages, &stop_seq, &distances, loop_distance)
+            });
+            let capacity = match capacity_sum {
+                Some(sum) if service_count > 0 => Some((sum + service_count / 2) / service_count),
+                _ => capacities.get(route).copied().or(opts.capacity),
+            };
+
+            let out = match opts.view {
+                View::Arcs => visualise_one(
+                    &patronages,
+                    &stop_seq,
+                    &stop_names,
+                    service_count,
+                    route,
+                    direction,
+                    ftime,
+                    convert_monthname(&month),
+                    &year,
+                    &DrawOptions {
+                        colour_by,
+                        arc_classes: arc_classes.as_ref(),
+                        palette,
+                        css_path: css.as_ref(),
+                        scale: opts.scale,
+                        network_max,
+                        thresholds: Thresholds { min_flow: opts.min_flow, top_k: opts.top_k },
+                        layout: &opts.layout,
+                        provenance: provenance.as_ref(),
+                        focus,
+                        capacity,
+                    },
+                    (opts.layout.spacing == Spacing::Distance).then_some(distances.as_slice()),
+                    trips.as_ref().filter(|_| opts.trip_lengths),
+                ),
+                View::Map => map_one(
+                    &patronages,
+                    &stop_seq,
+                    &stop_names,
+                    &coords,
+                    &legs,
+                    service_count,
+                    route,
+                    direction,
+                    ftime.as_deref(),
+                    convert_monthname(&month),
+                    &year,
+                    css.as_ref(),
+                    opts.scale,
+                    network_max,
+                    trips.as_ref().filter(|_| opts.trip_lengths),
+                ),
+            }
             .context("Error generating SVG")?;
 
+            let out = render(&out, opts.format, opts.dpi, opts.max_width)
+                .context("Error rendering diagram")?;
+
             write_outfile(
                 &out_dir,
-                &format!("{}_{}.svg", route, direction),
+                &format!("{}_{}.{}", route, direction, opts.format.extension()),
                 &month,
                 &year,
                 ftime,
                 &out,
             )
-            .context("Error writing SVG file")?;
+            .context("Error writing diagram file")?;
 
             // do this right at the end, so that if anything else causes a skip,
             // it won't be in the index
             rd_tree.entry(route.clone()).or_insert_with(Vec::new).push(direction.clone());
+            if let Some(trips) = trips {
+                all_trips.push((
+                    (route.clone(), direction.clone()),
+                    patronages.values().sum(),
+                    trips,
+                ));
+            }
+            if let Some(h) = history.as_deref_mut() {
+                let mut frame = Frame {
+                    year: year.clone(),
+                    month: month.clone(),
+                    label: format!("{} {}", convert_monthname(&month), year),
+                    boardings: patronages.values().sum(),
+                    peak_load: segment_loads(&patronages, &stop_seq).into_iter().max().unwrap_or(0),
+                    service_count,
+                    ..Frame::default()
+                };
+                // trends only need the month's metrics; animations need all of it
+                if opts.animate {
+                    frame.patronages.clone_from(&patronages);
+                    frame.stop_sequence.clone_from(&stop_seq);
+                    frame.stop_names.clone_from(&stop_names);
+                    frame.categories = categories;
+                    if colour_by == ColourBy::Distance {
+                        frame.coords.clone_from(&coords);
+                    }
+                    if opts.layout.spacing == Spacing::Distance {
+                        frame.distances.clone_from(&distances);
+                    }
+                }
+                h.entry((route.clone(), direction.clone())).or_default().push(frame);
+            }
+
+            if opts.export.contains(&Export::Od) {
+                let rows = od_rows(&patronages, &stop_seq, &stop_names);
+                let stem = format!("{route}_{direction}_od");
+                write_outfile(
+                    &out_dir,
+                    &format!("{stem}.csv"),
+                    &month,
+                    &year,
+                    ftime,
+                    to_csv(&rows)?,
+                )
+                .context("Error writing OD matrix")?;
+                write_outfile(
+                    &out_dir,
+                    &format!("{stem}.json"),
+                    &month,
+                    &year,
+                    ftime,
+                    to_json(&rows)?,
+                )
+                .context("Error writing OD matrix")?;
+                #[cfg(feature = "parquet")]
+                write_outfile(
+                    &out_dir,
+                    &format!("{stem}.parquet"),
+                    &month,
+                    &year,
+                    ftime,
+                    export::od_parquet(&rows)?,
+                )
+                .context("Error writing OD matrix")?;
+            }
+
+            if opts.export.contains(&Export::StopLoads) {
+                let rows = stop_rows(&patronages, &stop_seq, &stop_names, &coords);
+                write_outfile(
+                    &out_dir,
+                    &format!("{route}_{direction}_stops.csv"),
+                    &month,
+                    &year,
+                    ftime,
+                    to_csv(&rows)?,
+                )
+                .context("Error writing stop table")?;
+            }
+
+            if opts.export.contains(&Export::Geojson) {
+                write_outfile(
+                    &out_dir,
+                    &format!("{route}_{direction}.geojson"),
+                    &month,
+                    &year,
+                    ftime,
+                    geojson(&patronages, &stop_seq, &stop_names, &coords, &legs)?,
+                )
+                .context("Error writing GeoJSON")?;
+            }
+
+            let loads = segment_loads(&patronages, &stop_seq);
+            let peak = peak_segment(&loads);
+            for (i, load) in loads.iter().enumerate() {
+                let to_stop = stop_seq[(i + 1) % stop_seq.len()];
+                // nobody goes around the loop on most routes
+                if i + 1 == stop_seq.len() && *load == 0 {
+                    continue;
+                }
+                load_rows.push(LoadFactorRow {
+                    route,
+                    direction,
+                    from_stop: stop_seq[i],
+                    to_stop,
+                    load: *load,
+                    services: service_count,
+                    capacity,
+                    mean_load_factor: capacity
+                        .map(|c| f64::from(c) * f64::from(service_count))
+                        .filter(|c| *c > 0.0)
+                        .map(|c| f64::from(*load) / c),
+                    peak: peak == Some(i),
+                });
+            }
 
             completed += 1;
         }
 
+        if opts.export.contains(&Export::TripLengths) {
+            let mut summary = csv::Writer::from_writer(Vec::new());
+            let mut distribution = csv::Writer::from_writer(Vec::new());
+            for ((route, direction), boardings, trips) in &all_trips {
+                summary.serialize(TripLengthRow {
+                    route,
+                    direction,
+                    boardings: *boardings,
+                    passenger_km: trips.passenger_km,
+                    mean_km: trips.mean_km,
+                    median_km: trips.median_km,
+                })?;
+                for bin in &trips.distribution {
+                    distribution.serialize(LengthBinRow {
+                        route,
+                        direction,
+                        from_km: bin.from_km,
+                        to_km: bin.to_km,
+                        passengers: bin.passengers,
+                    })?;
+                }
+            }
+            write_outfile(
+                &out_dir,
+                "trip_lengths.csv",
+                &month,
+                &year,
+                ftime,
+                summary.into_inner()?,
+            )
+            .context("Error writing trip lengths")?;
+            write_outfile(
+                &out_dir,
+                "trip_length_distribution.csv",
+                &month,
+                &year,
+                ftime,
+                distribution.into_inner()?,
+            )
+            .context("Error writing trip length distribution")?;
+        }
+
+        if opts.export.contains(&Export::LoadFactors) {
+            write_outfile(&out_dir, "load_factors.csv", &month, &year, ftime, to_csv(&load_rows)?)
+                .context("Error writing load factors")?;
+        }
+
         // Write index.html if not a --one
         if one.len() != 2 {
-            write_index_html(&rd_tree, &out_dir, &month, &year, ftime)?;
+            write_index_html(&rd_tree, &out_dir, &month, &year, ftime, opts.format.extension())?;
         }
 
         info!(
@@ -642,6 +1415,7 @@ fn write_index_html(
     month: &str,
     year: &str,
     ftime: &Option<String>,
+    extension: &str,
 ) -> Result<(), anyhow::Error> {
     let mut index_html = format!(
         r#"<html>
@@ -657,13 +1431,13 @@ fn write_index_html(
     for (k, v) in rd_tree {
         write!(index_html, "<tr>")?;
         for d in v {
-            write!(index_html, r#"<td><a href="{}_{}.svg">{} {}</a></td>"#, k, d, k, d)?;
+            write!(index_html, r#"<td><a href="{k}_{d}.{extension}">{k} {d}</a></td>"#)?;
         }
         writeln!(index_html, "</tr>")?;
     }
     write!(index_html, "</table>\n</body>\n</html>")?;
 
-    write_outfile(out_dir, "index.html", month, year, ftime, &index_html)
+    write_outfile(out_dir, "index.html", month, year, ftime, index_html)
         .context("Error writing index.html")
 }
 
@@ -674,13 +1448,13 @@ fn write_outfile(
     month: &str,
     year: &str,
     ftime: &Option<String>,
-    contents: &str,
+    contents: impl AsRef<[u8]>,
 ) -> std::result::Result<(), std::io::Error> {
     let mut outfile = PathBuf::from(&out_dir);
     outfile.push(&year);
     outfile.push(&month);
     if let Some(f) = ftime.as_ref() {
-        outfile.push(f.replace(' ', "_").replace('(', "").replace(')', "").replace(':', ""));
+        outfile.push(ftime_dirname(f));
     }
     std::fs::create_dir_all(&outfile)?;
     outfile.push(filename);
@@ -688,6 +1462,29 @@ fn write_outfile(
     Ok(())
 }
 
+fn write_batch_outfile(
+    out_dir: &Path,
+    subdir: &str,
+    filename: &str,
+    ftime: Option<&str>,
+    contents: impl AsRef<[u8]>,
+) -> std::result::Result<(), std::io::Error> {
+    //! Like `write_outfile`, but for things spanning a whole batch, which go in `out_dir/subdir`
+    let mut outfile = out_dir.join(subdir);
+    if let Some(f) = ftime {
+        outfile.push(ftime_dirname(f));
+    }
+    std::fs::create_dir_all(&outfile)?;
+    outfile.push(filename);
+    std::fs::write(outfile, contents)?;
+    Ok(())
+}
+
+fn ftime_dirname(ftime: &str) -> String {
+    //! A directory name for a time-of-day filter
+    ftime.replace(['(', ')', ':'], "").replace(' ', "_")
+}
+
 fn convert_direction(from: &str) -> &'static str {
     //! Convert a direction name like "inbound" to a "0" or "1"  
     let froml = from.to_lowercase();
@@ -0,0 +1,173 @@
+//! Draw a route as a flow map, with stops where they really are
+
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::path::PathBuf;
+
+use anyhow::{bail, Result};
+
+use crate::gtfs::{Leg, Quantity, StopId};
+use crate::metrics::{peak_segment, TripLengths};
+use crate::visualise::{escape, load_css, make_title, segment_loads, Scale};
+
+/// Length of the longer side of the map, in pixels
+const MAP_SIZE: f64 = 1500.0;
+/// Padding around the map, in pixels
+const MARGIN: f64 = 100.0;
+/// Extra room to the right for stop names, in pixels
+const LABEL_ROOM: f64 = 400.0;
+/// Height of the header, which holds the title and legend, in pixels
+const HEADER: f64 = 350.0;
+/// Width of the busiest segment at the per-route scale, in pixels
+const MAX_WIDTH: f64 = 40.0;
+
+#[allow(clippy::too_many_arguments)]
+#[allow(clippy::too_many_lines)]
+/// Draw a single route as a map.
+/// Stops are placed at their coordinates (`coords`, as latitude and longitude)
+/// and joined in order of `stop_sequence` by lines as wide as the load between them,
+/// following `legs` (one between each pair of consecutive stops) where given.
+pub fn map_one(
+    patronages: &BTreeMap<(StopId, StopId), Quantity>,
+    stop_sequence: &[StopId],
+    stop_names: &BTreeMap<StopId, String>,
+    coords: &BTreeMap<StopId, (f64, f64)>,
+    legs: &[Leg],
+    service_count: Quantity,
+    route_name: &str,
+    direction: &str,
+    ftime: Option<&str>,
+    month: &str,
+    year: &str,
+    css_path: Option<&PathBuf>,
+    scale: Scale,
+    network_max: Quantity,
+    trips: Option<&TripLengths>,
+) -> Result<String> {
+    let points: Vec<(f64, f64)> =
+        stop_sequence.iter().filter_map(|stop| coords.get(stop).copied()).collect();
+    if points.len() != stop_sequence.len() || points.is_empty() {
+        bail!("Missing stop coordinates for {} {}", route_name, direction);
+    }
+
+    // An equirectangular projection is plenty at the scale of a single route
+    let (lat_min, lat_max, lon_min, lon_max) =
+        points.iter().chain(legs.iter().flat_map(|l| &l.path)).fold(
+            (f64::INFINITY, f64::NEG_INFINITY, f64::INFINITY, f64::NEG_INFINITY),
+            |(a, b, c, d), (lat, lon)| (a.min(*lat), b.max(*lat), c.min(*lon), d.max(*lon)),
+        );
+    let aspect = lat_min.midpoint(lat_max).to_radians().cos();
+    let (map_w, map_h) = ((lon_max - lon_min) * aspect, lat_max - lat_min);
+    let px = MAP_SIZE / map_w.max(map_h).max(f64::EPSILON);
+    let project = |(lat, lon): (f64, f64)| {
+        (((lon - lon_min) * aspect).mul_add(px, MARGIN), (lat_max - lat).mul_add(px, HEADER))
+    };
+
+    let doc_width = map_w.mul_add(px, 2.0f64.mul_add(MARGIN, LABEL_ROOM));
+    let doc_height = map_h.mul_add(px, HEADER + MARGIN);
+
+    let loads = segment_loads(patronages, stop_sequence);
+    let load_max = loads.iter().copied().max().unwrap_or(0);
+    let peak = peak_segment(&loads);
+
+    // passengers per pixel of line width
+    let per_px = match scale {
+        Scale::PerRoute => f64::from(load_max.max(1)) / MAX_WIDTH,
+        Scale::Global => f64::from(network_max.max(1)) / MAX_WIDTH,
+        Scale::Fixed(n) => n,
+    };
+
+    let css = load_css(css_path)?;
+
+    // Segments: each stop to the next, and the last back to the first if anyone's still on board
+    let mut segments = String::new();
+    for (i, load) in loads.iter().enumerate() {
+        let j = (i + 1) % stop_sequence.len();
+        if *load == 0 {
+            continue;
+        }
+        let path = legs.get(i).map_or_else(|| vec![points[i], points[j]], |leg| leg.path.clone());
+        let mut line = String::new();
+        for point in path {
+            let (x, y) = project(point);
+            write!(line, "{x:.2},{y:.2} ")?;
+        }
+        let from = stop_sequence[i];
+        let to = stop_sequence[j];
+        let name = |s: &StopId| escape(stop_names.get(s).map_or(&s.to_string(), |n| n));
+        let loopy = if j == 0 { " loop" } else { "" };
+        let peaky = if peak == Some(i) { " peak" } else { "" };
+        writeln!(
+            segments,
+            r#"<polyline class="segment{loopy}{peaky}" points="{}" stroke-width="{:.3}"><title>from: {}
+to: {}
+on board: {load}</title></polyline>"#,
+            line.trim_end(),
+            f64::from(*load) / per_px,
+            name(&from),
+            name(&to),
+        )?;
+    }
+
+    // Stops and their names
+    let mut stops = String::new();
+    let mut labels = String::new();
+    for (idx, (stop, point)) in stop_sequence.iter().zip(points.iter()).enumerate() {
+        let (x, y) = project(*point);
+        let name = escape(stop_names.get(stop).map_or(&stop.to_string(), |n| n));
+        writeln!(
+            stops,
+            r#"<circle class="markers" data-stop="{idx}" data-name="{name}" cx="{x:.2}" cy="{y:.2}" r="8" />"#
+        )?;
+        for t_c in &["keyline", "foreground"] {
+            writeln!(
+                labels,
+                r#"<text class="{t_c} maplabel" x="{:.2}" y="{:.2}">{name}</text>"#,
+                x + 15.0,
+                y + 6.0
+            )?;
+        }
+    }
+
+    // Legend: a sample line width
+    let sample = (f64::from(load_max) / 2.0).max(1.0).round();
+    let legend = format!(
+        r#"<g class="legend"><text class="legendhead" x="{MARGIN}" y="250">Line width: passengers on board</text>
+<line class="segment" x1="{MARGIN}" x2="{}" y1="290" y2="290" stroke-width="{:.3}" /><text class="legendtxt" x="{}" y="290">{sample} passengers</text></g>"#,
+        MARGIN + 50.0,
+        sample / per_px,
+        MARGIN + 65.0,
+    );
+
+    let boards_count: Quantity = patronages.values().sum();
+    let title = make_title(
+        doc_width,
+        route_name,
+        direction,
+        month,
+        year,
+        boards_count,
+        service_count,
+        ftime,
+        trips,
+    );
+
+    Ok(format!(
+        // glorious hack: include_str! is eagerly evaluated
+        include_str!("template.svg"),
+        doc_width,
+        doc_height,
+        "",
+        css,
+        "translate(0,0)",
+        "",
+        segments,
+        "",
+        labels,
+        "",
+        stops,
+        title,
+        legend,
+        ""
+    ))
+}
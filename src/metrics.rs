@@ -0,0 +1,235 @@
+//! Statistics about a route's patronage, for subtitles and CSV exports
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::gtfs::{Quantity, StopId};
+
+/// Bin widths to choose from for the trip length distribution, in kilometres
+const BIN_WIDTHS: [f64; 7] = [0.5, 1.0, 2.0, 5.0, 10.0, 20.0, 50.0];
+/// The most bins a trip length distribution should have
+const MAX_BINS: f64 = 20.0;
+
+/// How far passengers travel on a route
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TripLengths {
+    /// Total distance travelled by all passengers
+    pub passenger_km: f64,
+    /// Mean trip length, in kilometres
+    pub mean_km: f64,
+    /// Median trip length, in kilometres
+    pub median_km: f64,
+    /// Passengers by trip length
+    pub distribution: Vec<LengthBin>,
+}
+
+/// Passengers whose trips were at least `from_km` but less than `to_km` long
+#[derive(Clone, Debug, PartialEq)]
+pub struct LengthBin {
+    /// Shortest trip length in the bin
+    pub from_km: f64,
+    /// Trip length just past the end of the bin
+    pub to_km: f64,
+    /// Passengers with a trip length in the bin
+    pub passengers: Quantity,
+}
+
+/// One row of the trip lengths CSV
+#[derive(Debug, Serialize)]
+pub struct TripLengthRow<'a> {
+    /// Route name
+    pub route: &'a str,
+    /// Direction name
+    pub direction: &'a str,
+    /// Total boardings
+    pub boardings: Quantity,
+    /// See [`TripLengths`]
+    pub passenger_km: f64,
+    /// See [`TripLengths`]
+    pub mean_km: f64,
+    /// See [`TripLengths`]
+    pub median_km: f64,
+}
+
+/// One row of the trip length distribution CSV
+#[derive(Debug, Serialize)]
+pub struct LengthBinRow<'a> {
+    /// Route name
+    pub route: &'a str,
+    /// Direction name
+    pub direction: &'a str,
+    /// See [`LengthBin`]
+    pub from_km: f64,
+    /// See [`LengthBin`]
+    pub to_km: f64,
+    /// See [`LengthBin`]
+    pub passengers: Quantity,
+}
+
+pub fn trip_lengths(
+    patronages: &BTreeMap<(StopId, StopId), Quantity>,
+    stop_sequence: &[StopId],
+    distances: &[f64],
+    loop_distance: f64,
+) -> TripLengths {
+    //! Trip lengths along `stop_sequence`, given the `distances` in metres between consecutive stops
+    //! and the `loop_distance` from the last stop back around to the first.
+    //! Trips between stops that aren't in the sequence are left out.
+    #![allow(clippy::cast_possible_truncation)]
+    #![allow(clippy::cast_sign_loss)]
+    let seqi: BTreeMap<StopId, usize> =
+        stop_sequence.iter().enumerate().map(|(i, stop)| (*stop, i)).collect();
+
+    // how far along the route each stop is, and how far it is all the way around
+    let mut along = vec![0.0];
+    for d in distances {
+        along.push(along.last().copied().unwrap_or(0.0) + d);
+    }
+    let circuit = along.last().copied().unwrap_or(0.0) + loop_distance;
+
+    // (length in km, passengers)
+    let mut trips: Vec<(f64, Quantity)> = patronages
+        .iter()
+        .filter(|(_, qty)| **qty > 0)
+        .filter_map(|((from, to), qty)| {
+            let (from_idx, to_idx) = (*seqi.get(from)?, *seqi.get(to)?);
+            let (from_m, to_m) = (along.get(from_idx)?, along.get(to_idx)?);
+            // consecutive stops can share a place, so it's the order that says what wraps around
+            let metres = if to_idx > from_idx { to_m - from_m } else { circuit - from_m + to_m };
+            Some((metres / 1000.0, *qty))
+        })
+        .collect();
+    trips.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let passengers: Quantity = trips.iter().map(|t| t.1).sum();
+    if passengers == 0 {
+        return TripLengths::default();
+    }
+    let passenger_km: f64 = trips.iter().map(|(km, qty)| km * f64::from(*qty)).sum();
+
+    // the trip that the middle passenger took
+    let mut seen = 0;
+    let median_km = trips
+        .iter()
+        .find(|(_, qty)| {
+            seen += qty;
+            2 * seen >= passengers
+        })
+        .map_or(0.0, |t| t.0);
+
+    let longest = trips.last().map_or(0.0, |t| t.0);
+    let width = BIN_WIDTHS.iter().copied().find(|w| longest / w < MAX_BINS).unwrap_or(100.0);
+    let mut distribution: Vec<LengthBin> = (0..=(longest / width).floor() as usize)
+        .map(|i| LengthBin {
+            from_km: i as f64 * width,
+            to_km: (i + 1) as f64 * width,
+            passengers: 0,
+        })
+        .collect();
+    for (km, qty) in &trips {
+        if let Some(bin) = distribution.get_mut((km / width).floor() as usize) {
+            bin.passengers += qty;
+        }
+    }
+
+    TripLengths {
+        passenger_km,
+        mean_km: passenger_km / f64::from(passengers),
+        median_km,
+        distribution,
+    }
+}
+
+/// One row of the load factors CSV: the load between a pair of consecutive stops
+#[derive(Debug, Serialize)]
+pub struct LoadFactorRow<'a> {
+    /// Route name
+    pub route: &'a str,
+    /// Direction name
+    pub direction: &'a str,
+    /// Stop at the start of the segment
+    pub from_stop: StopId,
+    /// Stop at the end of the segment
+    pub to_stop: StopId,
+    /// Passengers on board
+    pub load: Quantity,
+    /// Estimated number of services
+    pub services: Quantity,
+    /// Capacity of each service, if known
+    pub capacity: Option<Quantity>,
+    /// `load` ÷ (`services` × `capacity`): the month's load factor averaged over its services
+    pub mean_load_factor: Option<f64>,
+    /// Whether this is the busiest segment on the route
+    pub peak: bool,
+}
+
+pub fn peak_segment(loads: &[Quantity]) -> Option<usize> {
+    //! Index of the (first) busiest segment, if anyone is on board at all
+    let max = loads.iter().copied().max().filter(|m| *m > 0)?;
+    loads.iter().position(|l| *l == max)
+}
+
+pub fn load_capacities(path: &Path) -> Result<BTreeMap<String, Quantity>> {
+    //! Read a CSV of `route,capacity` (with a header row): passengers per service on each route
+    let mut rdr = csv::Reader::from_path(path)
+        .with_context(|| format!("Error opening capacity table {}", path.display()))?;
+    let mut capacities = BTreeMap::new();
+    for row in rdr.deserialize() {
+        let (route, capacity): (String, Quantity) = row.context("Error reading capacity table")?;
+        capacities.insert(route, capacity);
+    }
+    Ok(capacities)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn co_located_stops_are_not_wraparounds() {
+        // stops 2 and 3 are in the same place
+        let stop_sequence = [1, 2, 3, 4];
+        let distances = [1000.0, 0.0, 1000.0];
+        let patronages = BTreeMap::from([((2, 3), 10), ((4, 1), 10)]);
+        let trips = trip_lengths(&patronages, &stop_sequence, &distances, 500.0);
+        // 2 -> 3 goes nowhere; 4 -> 1 goes around the loop
+        assert!((trips.passenger_km - 5.0).abs() < 1e-9);
+        assert!(trips.median_km.abs() < 1e-9);
+        let bins: Vec<Quantity> = trips.distribution.iter().map(|b| b.passengers).collect();
+        assert_eq!(bins, [10, 10]);
+    }
+
+    #[test]
+    fn peak_segment_picks_the_first_of_a_tie() {
+        assert_eq!(peak_segment(&[3, 7, 7, 2]), Some(1));
+    }
+
+    #[test]
+    fn peak_segment_needs_someone_on_board() {
+        assert_eq!(peak_segment(&[0, 0, 0]), None);
+        assert_eq!(peak_segment(&[]), None);
+    }
+
+    #[test]
+    fn capacities_are_read_by_route() -> Result<()> {
+        let mut file = tempfile::NamedTempFile::new()?;
+        std::io::Write::write_all(&mut file, b"route,capacity\n100,60\n200,80\n")?;
+        let capacities = load_capacities(file.path())?;
+        assert_eq!(
+            capacities,
+            BTreeMap::from([(String::from("100"), 60), (String::from("200"), 80)])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn capacities_must_be_whole_numbers() -> Result<()> {
+        let mut file = tempfile::NamedTempFile::new()?;
+        std::io::Write::write_all(&mut file, b"route,capacity\n100,lots\n")?;
+        assert!(load_capacities(file.path()).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,115 @@
+//! Render SVG diagrams to raster or PDF output
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use resvg::tiny_skia::{Pixmap, Transform};
+use resvg::usvg::{fontdb, PostProcessingSteps, Tree, TreeParsing, TreePostProc};
+
+/// Family name of the embedded fonts
+const FONT_FAMILY: &str = "DejaVu Sans";
+
+/// Appended to the diagram's stylesheet so that all text uses the embedded fonts,
+/// whatever fonts happen to be installed on this machine.
+const FONT_CSS: &str = r#"
+text, .foreground, .keyline { font-family: "DejaVu Sans" }
+"#;
+
+/// Output file formats
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Scalable Vector Graphics, styled by CSS
+    Svg,
+    /// Portable Network Graphics, rasterised at `--dpi`
+    Png,
+    /// Portable Document Format, with vector graphics
+    Pdf,
+    /// A self-contained web page, with stops that can be clicked on and searched for
+    Html,
+}
+
+impl Format {
+    /// File extension, without the dot
+    pub const fn extension(self) -> &'static str {
+        match self {
+            Self::Svg => "svg",
+            Self::Png => "png",
+            Self::Pdf => "pdf",
+            Self::Html => "html",
+        }
+    }
+}
+
+/// Convert a diagram from SVG source into the specified format.
+///
+/// `dpi` is relative to the usual 96 pixels per inch of SVG.
+/// If the output would be wider than `max_width` pixels (at `dpi`), it is scaled down to fit.
+pub fn render(svg: &str, format: Format, dpi: f32, max_width: Option<u32>) -> Result<Vec<u8>> {
+    let scale_for = |tree: &Tree| {
+        let scale = dpi / 96.0;
+        max_width.map_or(scale, |mw| scale.min(mw as f32 / tree.size.width()))
+    };
+
+    Ok(match format {
+        Format::Svg => svg.as_bytes().to_vec(),
+        Format::Png => {
+            let tree = parse(svg)?;
+            let scale = scale_for(&tree);
+            let size = tree
+                .size
+                .to_int_size()
+                .scale_by(scale)
+                .context("Diagram is too small to render")?;
+            let mut pixmap = Pixmap::new(size.width(), size.height())
+                .context("Diagram is too large to render; try --max-width")?;
+            resvg::render(&tree, Transform::from_scale(scale, scale), &mut pixmap.as_mut());
+            pixmap.encode_png().context("Error encoding PNG")?
+        }
+        Format::Pdf => {
+            let tree = parse(svg)?;
+            // PDF is measured in points; a lower effective DPI makes a bigger page
+            let options =
+                svg2pdf::Options { dpi: 96.0 / scale_for(&tree), ..svg2pdf::Options::default() };
+            svg2pdf::convert_tree(&tree, options)
+        }
+        Format::Html => interactive(svg).into_bytes(),
+    })
+}
+
+fn interactive(svg: &str) -> String {
+    //! Embed the SVG in a web page which highlights a stop's arcs when it's clicked on.
+    //! Everything (styles, script) is inline, so the page works offline.
+    let svg = svg.find("<svg").map_or(svg, |start| &svg[start..]);
+    // the page title is the diagram's title
+    let title = svg
+        .find(r#"class="title""#)
+        .and_then(|start| {
+            let text = &svg[start..];
+            let open = text.find('>')? + 1;
+            let close = text.find('<')?;
+            text.get(open..close)
+        })
+        .unwrap_or("fluvial");
+    include_str!("interactive.html").replacen("<!-- title -->", title, 1).replacen(
+        "<!-- diagram -->",
+        svg,
+        1,
+    )
+}
+
+fn parse(svg: &str) -> Result<Tree> {
+    //! Parse our SVG, with text laid out in the embedded fonts
+    let svg = svg.replacen("]]>", &format!("{FONT_CSS}]]>"), 1);
+
+    let mut fonts = fontdb::Database::new();
+    fonts.load_font_data(include_bytes!("fonts/DejaVuSans.ttf").to_vec());
+    fonts.load_font_data(include_bytes!("fonts/DejaVuSans-Bold.ttf").to_vec());
+    fonts.set_sans_serif_family(FONT_FAMILY);
+
+    let options = resvg::usvg::Options {
+        font_family: String::from(FONT_FAMILY),
+        ..resvg::usvg::Options::default()
+    };
+    let mut tree = Tree::from_str(&svg, &options).context("Error parsing SVG for rendering")?;
+    tree.postprocess(PostProcessingSteps::default(), &fonts);
+    Ok(tree)
+}
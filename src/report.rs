@@ -0,0 +1,83 @@
+//! Reports over the whole network, rather than route by route
+
+use std::fmt::Write as FmtWrite;
+
+use anyhow::{Context, Result};
+use rusqlite::{named_params, Connection};
+use serde::{Deserialize, Serialize};
+use serde_rusqlite::from_rows;
+
+use crate::ftime_filter;
+use crate::gtfs::{Quantity, StopId};
+use crate::visualise::escape;
+
+/// A stop's patronage summed over every route which serves it
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct StopTotals {
+    /// Stop ID
+    pub stop_id: StopId,
+    /// Stop name, if the stop is in the GTFS
+    pub stop_name: Option<String>,
+    /// Latitude, if the stop is in the GTFS
+    pub stop_lat: Option<f64>,
+    /// Longitude, if the stop is in the GTFS
+    pub stop_lon: Option<f64>,
+    /// Passengers boarding here, on any route
+    pub boardings: Quantity,
+    /// Passengers alighting here, on any route
+    pub alightings: Quantity,
+    /// Boardings plus alightings
+    pub total: Quantity,
+    /// Number of routes with passengers boarding or alighting here
+    pub routes: Quantity,
+}
+
+pub fn stop_totals(db: &Connection, ftime: Option<&str>) -> Result<Vec<StopTotals>> {
+    //! Every stop's patronage across all routes, busiest first
+    let (ftime_ins, ftime_sub) = ftime_filter(ftime);
+
+    let stmt_txt = format!(
+        "WITH Ends AS (
+            SELECT origin_stop AS stop_id, route, quantity AS boardings, 0 AS alightings
+            FROM Patronage WHERE 1=1 {ftime_ins}
+            UNION ALL
+            SELECT destination_stop, route, 0, quantity
+            FROM Patronage WHERE 1=1 {ftime_ins}
+        )
+        SELECT Ends.stop_id AS stop_id, stop_name, stop_lat, stop_lon,
+            sum(boardings) AS boardings, sum(alightings) AS alightings,
+            sum(boardings) + sum(alightings) AS total, count(DISTINCT route) AS routes
+        FROM Ends LEFT JOIN Stops ON Stops.stop_id = Ends.stop_id
+        GROUP BY Ends.stop_id ORDER BY total DESC, Ends.stop_id;"
+    );
+    let mut stmt = db.prepare(&stmt_txt).context("Failed preparing statement.")?;
+    let rows = stmt.query(named_params! {":time": ftime_sub})?;
+    let totals = from_rows::<StopTotals>(rows).collect::<Result<Vec<_>, _>>()?;
+    Ok(totals)
+}
+
+pub fn stops_html(totals: &[StopTotals], title: &str) -> Result<String> {
+    //! A page with a table of `totals`, which can be sorted by any column
+    let mut rows = String::new();
+    for (rank, t) in totals.iter().enumerate() {
+        let coord = |c: Option<f64>| c.map_or_else(String::new, |c| format!("{c:.6}"));
+        writeln!(
+            rows,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            rank + 1,
+            t.stop_id,
+            escape(t.stop_name.as_deref().unwrap_or("")),
+            coord(t.stop_lat),
+            coord(t.stop_lon),
+            t.boardings,
+            t.alightings,
+            t.total,
+            t.routes
+        )?;
+    }
+    Ok(include_str!("stops.html").replacen("<!-- title -->", &escape(title), 2).replacen(
+        "<!-- rows -->",
+        &rows,
+        1,
+    ))
+}
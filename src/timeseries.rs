@@ -0,0 +1,451 @@
+//! Diagrams and reports spanning several months of a batch
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as FmtWrite;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use log::warn;
+use serde::Serialize;
+
+use crate::colour::{by_category, by_distance, by_stops, ColourBy, Palette};
+use crate::geometry::{Arc, Arcs, Body, Stops};
+use crate::gtfs::{topo_merge, Quantity, StopId};
+use crate::visualise::{
+    colour_key, escape, make_css, make_legend, name_lines, segment_loads, Layout,
+};
+
+/// How much of each frame's time it's held still for, before moving on to the next
+const HOLD: f64 = 0.5;
+/// Size of each sparkline on the trends page, in pixels
+const SPARK_WIDTH: f64 = 160.0;
+/// Height of each sparkline on the trends page, in pixels
+const SPARK_HEIGHT: f64 = 36.0;
+
+/// One month of a route, as drawn in a batch.
+/// Only animations need the patronages and stops; for trends alone, those are left empty.
+#[derive(Clone, Debug, Default)]
+pub struct Frame {
+    /// Year, e.g. "2019"
+    pub year: String,
+    /// Month as digits, e.g. "02"
+    pub month: String,
+    /// Month and year for people, e.g. "February 2019"
+    pub label: String,
+    /// Total boardings
+    pub boardings: Quantity,
+    /// Passengers on board the busiest segment
+    pub peak_load: Quantity,
+    /// {(origin, destination) : patronage}
+    pub patronages: BTreeMap<(StopId, StopId), Quantity>,
+    /// The route's stops that month
+    pub stop_sequence: Vec<StopId>,
+    /// Names of those stops
+    pub stop_names: BTreeMap<StopId, String>,
+    /// Estimated number of services that month
+    pub service_count: Quantity,
+    /// {(origin, destination) : most common category}, when colouring by ticket type, time or route
+    pub categories: BTreeMap<(StopId, StopId), String>,
+    /// {stop : (lat, lon)}, when colouring by distance
+    pub coords: BTreeMap<StopId, (f64, f64)>,
+    /// Distance between each pair of consecutive stops, in metres, with distance spacing
+    pub distances: Vec<f64>,
+}
+
+/// Every month drawn of each route/direction pair, in the order they were drawn
+pub type History = BTreeMap<(String, String), Vec<Frame>>;
+
+#[allow(clippy::too_many_lines)]
+/// Draw a route across all of its `frames` as an animated SVG (with SMIL).
+/// Stops are laid out just as for [`visualise_one`](crate::visualise::visualise_one),
+/// over all the stops of every frame, and stay put; arc widths and bars are to the same scale
+/// throughout and move smoothly from one frame to the next, holding still for a moment at each.
+/// Arcs keep one colour throughout, going by the totals over every frame.
+/// The animation loops in date order, taking `seconds` per frame.
+#[allow(clippy::too_many_arguments)]
+pub fn animate_one(
+    frames: &[Frame],
+    route_name: &str,
+    direction: &str,
+    colour_by: ColourBy,
+    palette: &Palette,
+    css_path: Option<&PathBuf>,
+    layout: &Layout,
+    seconds: f64,
+) -> Result<String> {
+    let mut frames: Vec<&Frame> = frames.iter().collect();
+    frames.sort_by(|a, b| (&a.year, &a.month).cmp(&(&b.year, &b.month)));
+    let stop_sequence =
+        topo_merge(frames.iter().map(|f| f.stop_sequence.iter().copied().collect()).collect())
+            .context("Error merging stop sequences")?;
+    let stop_names: BTreeMap<StopId, String> =
+        frames.iter().flat_map(|f| f.stop_names.clone()).collect();
+    let seqi: BTreeMap<StopId, usize> =
+        stop_sequence.iter().enumerate().map(|(i, stop)| (*stop, i)).collect();
+    let stop_count = stop_sequence.len();
+    let frame_count = frames.len();
+    let space = layout.space;
+
+    // each pair's category is the one from the month it carried the most passengers
+    let mut totals: BTreeMap<(StopId, StopId), Quantity> = BTreeMap::new();
+    let mut busiest: BTreeMap<(StopId, StopId), (Quantity, &String)> = BTreeMap::new();
+    for frame in &frames {
+        for (pair, quantity) in &frame.patronages {
+            *totals.entry(*pair).or_default() += quantity;
+            if let Some(category) = frame.categories.get(pair) {
+                let best = busiest.entry(*pair).or_insert((*quantity, category));
+                if *quantity > best.0 {
+                    *best = (*quantity, category);
+                }
+            }
+        }
+    }
+    let arc_classes = match colour_by {
+        ColourBy::Origin | ColourBy::Destination => None,
+        ColourBy::Stops => Some(by_stops(&totals, &stop_sequence, palette)),
+        ColourBy::Distance => {
+            let coords = frames.iter().flat_map(|f| f.coords.clone()).collect();
+            Some(by_distance(&totals, &coords, palette))
+        }
+        ColourBy::Ticket | ColourBy::Time | ColourBy::Route => {
+            let categories = busiest.into_iter().map(|(pair, (_, c))| (pair, c.clone())).collect();
+            Some(by_category(&categories, palette))
+        }
+    };
+
+    // Stops and their names, which don't change
+    let name = |stop: &StopId| stop_names.get(stop).cloned().unwrap_or_else(|| stop.to_string());
+    let label_lines: Vec<Vec<String>> = stop_sequence
+        .iter()
+        .map(|stop| name_lines(&name(stop), layout.name_length, layout.wrap_names))
+        .collect();
+    // the distance between each pair of stops, from whichever month ran between them
+    let legs: BTreeMap<(StopId, StopId), f64> = frames
+        .iter()
+        .flat_map(|f| f.stop_sequence.windows(2).zip(&f.distances))
+        .map(|(pair, distance)| ((pair[0], pair[1]), *distance))
+        .collect();
+    let distances: Option<Vec<f64>> =
+        stop_sequence.windows(2).map(|pair| legs.get(&(pair[0], pair[1])).copied()).collect();
+    if distances.is_none() && !legs.is_empty() {
+        warn!("{route_name} {direction} never ran between some of its stops; spacing them evenly");
+    }
+    let stops = Stops::new(layout, &label_lines, distances.as_deref());
+
+    // each frame's passengers between every pair of stops (by index) in any frame
+    let pairs: BTreeSet<(usize, usize)> = frames
+        .iter()
+        .flat_map(|f| f.patronages.keys())
+        .filter_map(|(from, to)| Some((*seqi.get(from)?, *seqi.get(to)?)))
+        .filter(|(from_idx, to_idx)| from_idx != to_idx)
+        .collect();
+    let quantities: Vec<BTreeMap<(usize, usize), Quantity>> = frames
+        .iter()
+        .map(|frame| {
+            pairs
+                .iter()
+                .map(|(from_idx, to_idx)| {
+                    let key = (stop_sequence[*from_idx], stop_sequence[*to_idx]);
+                    ((*from_idx, *to_idx), *frame.patronages.get(&key).unwrap_or(&0))
+                })
+                .collect()
+        })
+        .collect();
+    let arc_height = stops.arc_height(pairs.iter().copied());
+
+    // one scale for every frame: the tightest squeeze in any of them
+    let squeeze = quantities
+        .iter()
+        .map(|q| {
+            let mut out = vec![0; stop_count];
+            let mut into = vec![0; stop_count];
+            for ((from_idx, to_idx), quantity) in q {
+                out[*from_idx] += quantity;
+                into[*to_idx] += quantity;
+            }
+            stops.squeeze(&out, &into)
+        })
+        .fold(0.0, f64::max);
+    // (if nobody travelled at all, any scale will do)
+    let squeeze = if squeeze > 0.0 { squeeze } else { 1.0 };
+    let px = |quantity: Quantity| f64::from(quantity) / squeeze;
+
+    let key = arc_classes.as_ref().map(|ac| colour_key(colour_by, ac));
+    let (legend, legend_height) = make_legend(key.as_ref(), squeeze * space, space, &[], None)?;
+
+    let loads: Vec<Vec<Quantity>> =
+        frames.iter().map(|f| segment_loads(&f.patronages, &stop_sequence)).collect();
+    let tallest = px(loads.iter().flatten().copied().max().unwrap_or(0));
+    let widest = px(quantities.iter().flat_map(BTreeMap::values).copied().max().unwrap_or(0));
+    let geometry = stops.fit(layout, legend_height, arc_height, widest, &label_lines, tallest);
+
+    // each arc's place in each frame, stacked up at their stops as they are that month;
+    // every frame has the same arcs in the same order, just with different widths
+    let arcs: Vec<Arcs> = quantities
+        .iter()
+        .map(|q| {
+            let widths = q.iter().map(|(pair, quantity)| (*pair, px(*quantity))).collect();
+            geometry.arcs(&widths, None)
+        })
+        .collect();
+
+    // each frame is held, then moves on to the next; the last moves back around to the first
+    let n = frame_count as f64;
+    let key_times: Vec<String> = (0..frame_count)
+        .flat_map(|k| [k as f64 / n, (k as f64 + HOLD) / n])
+        .chain([1.0])
+        .map(|t| format!("{t:.4}"))
+        .collect();
+    let key_times = key_times.join(";");
+    let dur = seconds * n;
+    let keyed = |values: Vec<String>| {
+        let first = values.first().cloned().unwrap_or_default();
+        let mut all: Vec<String> = values.into_iter().flat_map(|v| [v.clone(), v]).collect();
+        all.push(first);
+        all.join(";")
+    };
+    let animate = |attribute: &str, values: Vec<String>| {
+        format!(
+            r#"<animate attributeName="{attribute}" dur="{dur}s" repeatCount="indefinite" keyTimes="{key_times}" values="{}" />"#,
+            keyed(values)
+        )
+    };
+    // shown only while its frame is
+    let during = |k: usize| {
+        format!(
+            r#"opacity="{}"><animate attributeName="opacity" calcMode="discrete" dur="{dur}s" repeatCount="indefinite" keyTimes="0;{:.4};{:.4}" values="0;1;0" />"#,
+            u8::from(k == 0),
+            k as f64 / n,
+            (k as f64 + 1.0) / n,
+        )
+    };
+
+    let path = |k: usize, arc: &Arc| {
+        let (from, to) = (stop_sequence[arc.from_idx], stop_sequence[arc.to_idx]);
+        let class = arc_classes
+            .as_ref()
+            .and_then(|ac| ac.classes.get(&(from, to)))
+            .map_or_else(|| format!("f{from} t{to}"), |c| format!("f{from} t{to} c{c}"));
+        // this arc in every frame
+        let shapes: Vec<&Arc> = arcs
+            .iter()
+            .map(|a| if arc.to_idx > arc.from_idx { &a.forwards[k] } else { &a.wraparounds[k] })
+            .collect();
+        let counts: Vec<String> = frames
+            .iter()
+            .zip(&quantities)
+            .map(|(f, q)| format!("{}: {}", f.label, q[&(arc.from_idx, arc.to_idx)]))
+            .collect();
+        format!(
+            r#"<path class="arc {class}" data-from="{}" data-to="{}" d="{}" stroke-width="{:.5}"><title>from: {}
+to: {}
+{}</title>{}{}</path>
+"#,
+            arc.from_idx,
+            arc.to_idx,
+            arc.d,
+            arc.width,
+            escape(&name(&from)),
+            escape(&name(&to)),
+            counts.join("\n"),
+            animate("d", shapes.iter().map(|s| s.d.clone()).collect()),
+            animate("stroke-width", shapes.iter().map(|s| format!("{:.5}", s.width)).collect()),
+        )
+    };
+    let first = arcs.first().cloned().unwrap_or_default();
+    let paths_rev: String = first.wraparounds.iter().enumerate().map(|(k, a)| path(k, a)).collect();
+    let paths_fwd: String = first.forwards.iter().enumerate().map(|(k, a)| path(k, a)).collect();
+
+    let mut midline = geometry.mainline();
+    let mut labels = String::new();
+    let mut bargraph = String::new();
+    for (idx, (stop, lines)) in stop_sequence.iter().zip(label_lines.iter()).enumerate() {
+        labels.push_str(&geometry.label(idx, lines)?);
+
+        // the bar grows and shrinks, and its number changes as each frame arrives
+        let heights: Vec<f64> = loads.iter().map(|l| px(l[idx])).collect();
+        let tops: Vec<String> = heights.iter().map(|h| (geometry.height - h).to_string()).collect();
+        let height = heights.first().copied().unwrap_or(0.0);
+        bargraph.push_str(&geometry.bar(idx, height, "", &animate("y2", tops)));
+        for (k, l) in loads.iter().enumerate() {
+            write!(
+                bargraph,
+                "<g {}{}</g>",
+                during(k),
+                geometry.bar_text(idx, &l[idx].to_string())?
+            )?;
+        }
+
+        let data = format!(r#" data-name="{}""#, escape(&name(stop)));
+        midline.push_str(&geometry.marker(idx, "", &data));
+    }
+
+    let page = geometry.page();
+    let css = make_css(colour_by, arc_classes.as_ref(), palette, css_path, &stop_sequence)?;
+
+    // the subtitle says which month it is, switching as each frame arrives
+    let mut title = format!(
+        r#"<text class="title" x="{}" y="100">{} {}</text>"#,
+        page.width / 2.0,
+        escape(route_name),
+        escape(direction)
+    );
+    for (k, frame) in frames.iter().enumerate() {
+        write!(
+            title,
+            r#"
+    <text class="subtitle" x="{}" y="150" {}{}: {} boardings; est. {} services</text>"#,
+            page.width / 2.0,
+            during(k),
+            frame.label,
+            frame.boardings,
+            frame.service_count,
+        )?;
+    }
+
+    let body = Body { paths_rev, paths_fwd, labels, bargraph, midline };
+    Ok(page.svg(&css, &body, &title, &legend, ""))
+}
+
+/// One row of the trends CSV: a route's metrics for one month
+#[derive(Debug, Serialize)]
+pub struct TrendRow<'a> {
+    /// Route name
+    pub route: &'a str,
+    /// Direction name
+    pub direction: &'a str,
+    /// Year, e.g. "2019"
+    pub year: &'a str,
+    /// Month as digits, e.g. "02"
+    pub month: &'a str,
+    /// Total boardings
+    pub boardings: Quantity,
+    /// Estimated number of services
+    pub services: Quantity,
+    /// `boardings` ÷ `services`, if there were any services
+    pub boardings_per_service: Option<f64>,
+    /// Passengers on board the busiest segment
+    pub peak_load: Quantity,
+}
+
+pub fn trend_rows(history: &History) -> Vec<TrendRow<'_>> {
+    //! Every route's metrics for every month, in route and then date order
+    let mut rows = Vec::new();
+    for ((route, direction), frames) in history {
+        let mut frames: Vec<&Frame> = frames.iter().collect();
+        frames.sort_by(|a, b| (&a.year, &a.month).cmp(&(&b.year, &b.month)));
+        for frame in frames {
+            rows.push(TrendRow {
+                route,
+                direction,
+                year: &frame.year,
+                month: &frame.month,
+                boardings: frame.boardings,
+                services: frame.service_count,
+                boardings_per_service: (frame.service_count > 0)
+                    .then(|| f64::from(frame.boardings) / f64::from(frame.service_count)),
+                peak_load: frame.peak_load,
+            });
+        }
+    }
+    rows
+}
+
+fn sparkline(points: &[Option<f64>]) -> Result<String> {
+    //! A small line chart of `points`, evenly spaced, from zero up to the largest.
+    //! Missing points break the line.
+    let max = points.iter().flatten().copied().fold(0.0, f64::max);
+    let step = SPARK_WIDTH / (points.len().saturating_sub(1).max(1) as f64);
+    let y_of = |v: f64| if max > 0.0 { SPARK_HEIGHT * (1.0 - v / max) } else { SPARK_HEIGHT };
+
+    let mut lines = String::new();
+    for run in points.iter().enumerate().collect::<Vec<_>>().split(|(_, p)| p.is_none()) {
+        let mut line = String::new();
+        for (i, value) in run {
+            if let Some(v) = value {
+                write!(line, "{:.1},{:.1} ", *i as f64 * step, y_of(*v))?;
+            }
+        }
+        match run.len() {
+            0 => {}
+            1 => {
+                let (x, y) = line.trim_end().split_once(',').unwrap_or_default();
+                write!(lines, r#"<circle cx="{x}" cy="{y}" r="2" />"#)?;
+            }
+            _ => write!(lines, r#"<polyline points="{}" />"#, line.trim_end())?,
+        }
+    }
+    Ok(format!(
+        r#"<svg class="spark" width="{SPARK_WIDTH}" height="{}" viewBox="-2 -2 {} {}">{lines}</svg>"#,
+        SPARK_HEIGHT + 4.0,
+        SPARK_WIDTH + 4.0,
+        SPARK_HEIGHT + 4.0
+    ))
+}
+
+pub fn trends_html(rows: &[TrendRow], history: &History, title: &str) -> Result<String> {
+    //! A page of sparklines, one row per route/direction and one column per metric,
+    //! all over the same months so that they line up
+    let labels: BTreeMap<(&str, &str), &str> = history
+        .values()
+        .flatten()
+        .map(|f| ((f.year.as_str(), f.month.as_str()), f.label.as_str()))
+        .collect();
+    let months: Vec<(&str, &str)> = labels.keys().copied().collect();
+    let period = match (labels.values().next(), labels.values().last()) {
+        (Some(first), Some(last)) if first != last => format!("{first} to {last}"),
+        (Some(only), _) => String::from(*only),
+        _ => String::new(),
+    };
+
+    let mut by_route: BTreeMap<(&str, &str), Vec<&TrendRow>> = BTreeMap::new();
+    for row in rows {
+        by_route.entry((row.route, row.direction)).or_default().push(row);
+    }
+
+    let metrics: [fn(&TrendRow) -> Option<f64>; 4] = [
+        |r| Some(f64::from(r.boardings)),
+        |r| Some(f64::from(r.services)),
+        |r| r.boardings_per_service,
+        |r| Some(f64::from(r.peak_load)),
+    ];
+    let mut table = String::new();
+    for ((route, direction), route_rows) in &by_route {
+        write!(table, "<tr><th>{}</th><th>{}</th>", escape(route), escape(direction))?;
+        for metric in &metrics {
+            let points: Vec<Option<f64>> = months
+                .iter()
+                .map(|m| {
+                    route_rows.iter().find(|r| (r.year, r.month) == *m).and_then(|r| metric(r))
+                })
+                .collect();
+            let values: Vec<f64> = points.iter().flatten().copied().collect();
+            // the latest value, and how it compares to the first
+            let change = match (values.first(), values.last()) {
+                (Some(first), Some(last)) if values.len() > 1 && *first > 0.0 => {
+                    format!(" ({:+.0}%)", 100.0 * (last - first) / first)
+                }
+                _ => String::new(),
+            };
+            let latest = values.last().map_or_else(String::new, |v| {
+                // (only boardings per service isn't a whole number)
+                if *v < 100.0 && v.fract().abs() > f64::EPSILON {
+                    format!("{v:.1}")
+                } else {
+                    format!("{v:.0}")
+                }
+            });
+            write!(
+                table,
+                r#"<td>{}<span class="value">{latest}{change}</span></td>"#,
+                sparkline(&points)?
+            )?;
+        }
+        writeln!(table, "</tr>")?;
+    }
+
+    Ok(include_str!("trends.html")
+        .replacen("<!-- title -->", &escape(title), 2)
+        .replacen("<!-- period -->", &escape(&period), 1)
+        .replacen("<!-- rows -->", &table, 1))
+}
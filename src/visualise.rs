@@ -2,89 +2,360 @@
 
 use std::collections::BTreeMap;
 use std::fmt::Write as FmtWrite;
+use std::str::FromStr;
 
-use anyhow::Result;
-use hsluv::hsluv_to_hex;
-use rand::Rng;
+use anyhow::{bail, Result};
+use clap::ValueEnum;
 use std::path::PathBuf;
 
+use crate::colour::{ArcClasses, ColourBy, Palette};
+use crate::geometry::{Arc, Body, Geometry, Stops};
 use crate::gtfs::{Quantity, StopId};
+use crate::metrics::{peak_segment, TripLengths};
+
+// header constants; the header's text is sized by CSS, so these don't follow the `Layout`
+/// Spacing unit for the header in pixels
+const UNIT: f64 = 50.0;
+/// Left margin of the legend
+const MARGIN: f64 = 2.0 * UNIT;
+/// Height of the header, which holds the title and subtitle
+const HEADER: f64 = 4.5 * UNIT;
+/// Height of the footer, when there is one
+const FOOTER: f64 = 1.5 * UNIT;
+/// Narrowest the page gets, so that short routes still fit the title, legend and footer
+pub const MIN_WIDTH: f64 = 2.0 * MARGIN + 16.0 * UNIT;
+
+/// Geometry of the diagram. Lengths other than `space` and `font_size` are in units of `space`.
+#[derive(clap::Args, Clone, Copy, Debug)]
+pub struct Layout {
+    /// Spacing unit in pixels
+    #[arg(long = "space", default_value_t = 50.0, value_names(&["pixels"]), value_parser = positive)]
+    pub space: f64,
+    /// Distance between stops (on average, with distance spacing)
+    #[arg(long = "between", default_value_t = 2.5, value_names(&["spaces"]), value_parser = positive)]
+    pub between: f64,
+    /// Space stops evenly, or in proportion to the distance between them along the route
+    #[arg(long = "spacing", value_enum, default_value_t = Spacing::Uniform)]
+    pub spacing: Spacing,
+    /// With distance spacing, the least distance between stops [default: fit their labels]
+    #[arg(long = "min-spacing", value_names(&["spaces"]), value_parser = positive)]
+    pub min_spacing: Option<f64>,
+    /// Minimum gap between stops' arcs
+    #[arg(long = "min-gap", default_value_t = 0.5, value_names(&["spaces"]), value_parser = not_negative)]
+    pub min_gap: f64,
+    /// Height of the section for stop names and the bargraph [default: fit the longest name]
+    #[arg(long = "text-section", value_names(&["spaces"]), value_parser = positive)]
+    pub text_section: Option<f64>,
+    /// Padding at the left and right edges
+    #[arg(long = "edge", default_value_t = 2.0, value_names(&["spaces"]), value_parser = not_negative)]
+    pub edge: f64,
+    /// Font size of the stop labels, in pixels
+    #[arg(long = "font-size", default_value_t = 25.0, value_names(&["pixels"]), value_parser = positive)]
+    pub font_size: f64,
+    /// Shorten stop names longer than this many characters
+    #[arg(long = "name-length", value_names(&["chars"]))]
+    pub name_length: Option<usize>,
+    /// Wrap long stop names onto more lines instead of shortening them
+    #[arg(long = "wrap-names", requires = "name_length")]
+    pub wrap_names: bool,
+    /// Lay stops out left to right (landscape) or top to bottom (portrait)
+    #[arg(long = "orientation", value_enum, default_value_t = Orientation::Landscape)]
+    pub orientation: Orientation,
+    /// Flatten arcs so none is taller than this many pixels (default 500 if given without a value)
+    #[arg(long = "compact", value_names(&["pixels"]), num_args = 0..=1, default_missing_value = "500", value_parser = positive)]
+    pub compact: Option<f64>,
+}
 
-// spacing constants
-/// Spacing unit in pixels
-const SPACE: f64 = 50.0;
-/// Distance between stops
-const BETWEEN: f64 = 2.5 * SPACE;
-/// Minimum distance
-const MIN_GAP: f64 = 0.5 * SPACE;
-/// Height of the text section
-const TEXT_SECTION: f64 = 11.0 * SPACE;
-/// Edge padding
-const EXTRA: f64 = 2.0 * SPACE;
-
-fn colour_list(count: usize) -> Vec<String> {
-    //! Create a vector of hex colour codes, with evenly-spaced hues
-    //! plus a little bit of variance in saturation and lightness.
-    let mut out = Vec::<String>::new();
-    let mut rng = rand::thread_rng();
-    for k in 0..count {
-        let hue = 360.0 * (k as f64) / (count as f64);
-        let sat_var: f64 = rng.gen();
-        let sat = 10.0f64.mul_add(sat_var, 90.0);
-        let val = match k % 4 {
-            1 => 50.0,
-            3 => 60.0,
-            _ => 55.0,
-        };
-        out.push(hsluv_to_hex((hue, sat, val)));
+impl Layout {
+    pub fn check(&self) -> Result<()> {
+        //! Arcs need some room between stops once the gap is taken out,
+        //! or their widths come out negative
+        if self.between <= self.min_gap {
+            bail!(
+                "--between ({} spaces) must be more than --min-gap ({} spaces)",
+                self.between,
+                self.min_gap
+            );
+        }
+        if let Some(m) = self.min_spacing.filter(|m| *m <= self.min_gap) {
+            bail!(
+                "--min-spacing ({m} spaces) must be more than --min-gap ({} spaces)",
+                self.min_gap
+            );
+        }
+        Ok(())
     }
-    out
 }
 
-fn jumbled<T>(input: Vec<T>) -> Vec<T>
-where
-    T: Clone,
-{
-    //! If `input.len() >= 2`, returns a copy of `input` with its elements permuted in a star pattern.
-    //! Otherwise, returns `input`.
-
-    let len = input.len();
-
-    if len < 2 {
-        input
-    } else if len == 4 {
-        vec![input[1].clone(), input[3].clone(), input[0].clone(), input[2].clone()]
-    } else if len == 6 {
-        vec![
-            input[1].clone(),
-            input[3].clone(),
-            input[5].clone(),
-            input[0].clone(),
-            input[2].clone(),
-            input[4].clone(),
-        ]
-    } else {
-        // want g, L to be co-prime for a star pattern
-        // if m, n are coprime then more coprime pairs can be generated:
-        // (2m - n, m) and (2m + n, m) and (m + 2n, n)
-        // always coprime if m = n - 1 (for m >= 3)
-        let g = match len % 3 {
-            1 => len / 3, // (m + 2n, n) => 3k + 1, k = n
-            _ => match len % 9 {
-                6 => len / 3 - 1, // 3(3k-1) e.g. 15 so L/3 + 1 is div. by 3
-                _ => len / 3 + 1, // (2m + n, m) => 3k + 2, k = n AND ALSO other 3k's
+fn positive(s: &str) -> Result<f64> {
+    //! Parse a length that must be more than zero
+    match s.parse::<f64>() {
+        Ok(n) if n > 0.0 && n.is_finite() => Ok(n),
+        _ => bail!("expected a number more than 0"),
+    }
+}
+
+fn not_negative(s: &str) -> Result<f64> {
+    //! Parse a length that may be zero, but no less
+    match s.parse::<f64>() {
+        Ok(n) if n >= 0.0 && n.is_finite() => Ok(n),
+        _ => bail!("expected a number, 0 or more"),
+    }
+}
+
+/// How arc widths are scaled
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scale {
+    /// Fit each route's busiest stop into the space between stops
+    PerRoute,
+    /// Fit the network's busiest stop into the space between stops, for every route
+    Global,
+    /// A fixed number of passengers per pixel
+    Fixed(f64),
+}
+
+impl FromStr for Scale {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "per-route" => Ok(Self::PerRoute),
+            "global" => Ok(Self::Global),
+            _ => match s.strip_prefix("fixed:").map(str::parse::<f64>) {
+                Some(Ok(n)) if n > 0.0 => Ok(Self::Fixed(n)),
+                _ => bail!("expected global, per-route or fixed:N (N passengers per pixel)"),
             },
-        };
+        }
+    }
+}
+
+/// How stops are spread along the diagram
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Spacing {
+    /// The same distance between every pair of stops
+    Uniform,
+    /// In proportion to the distance between stops along the route
+    Distance,
+}
+
+/// Which way round the diagram goes
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Orientation {
+    /// Stops left to right, arcs above the line
+    Landscape,
+    /// Stops top to bottom, arcs to the right of the line
+    Portrait,
+}
+
+/// Part of a route to draw attention to; other arcs are faded
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Focus {
+    /// Trips to or from a stop
+    Stop(StopId),
+    /// Trips on board all the way from one stop to another
+    Segment(StopId, StopId),
+}
+
+/// Where a diagram's data came from, and how it was filtered, for its footer
+#[derive(Clone, Debug, Default)]
+pub struct Provenance {
+    /// Path or URL of the patronage data
+    pub patronage: String,
+    /// Path or URL of the GTFS (or positions file)
+    pub gtfs: String,
+    /// Descriptions of any filters applied
+    pub filters: Vec<String>,
+}
+
+/// Which origin-destination pairs are too small to draw individually.
+/// These are merged into a single "other" band at each origin.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Thresholds {
+    /// Pairs with fewer passengers than this are merged
+    pub min_flow: Quantity,
+    /// Only the largest `top_k` destinations from each origin are kept
+    pub top_k: Option<usize>,
+}
+
+/// How to draw a diagram, as opposed to what's in it
+#[derive(Clone, Copy, Debug)]
+pub struct DrawOptions<'a> {
+    /// What the arcs are coloured by
+    pub colour_by: ColourBy,
+    /// Colour classes for each arc, when they aren't simply coloured by stop
+    pub arc_classes: Option<&'a ArcClasses>,
+    /// Colours for each stop
+    pub palette: &'a Palette,
+    /// The user's CSS, if not the default
+    pub css_path: Option<&'a PathBuf>,
+    /// How arc widths are scaled
+    pub scale: Scale,
+    /// The network's busiest stop, for [`Scale::Global`]
+    pub network_max: Quantity,
+    /// Which pairs are merged rather than drawn
+    pub thresholds: Thresholds,
+    /// Diagram geometry
+    pub layout: &'a Layout,
+    /// Where the data came from; if given, there's also a legend explaining everything
+    pub provenance: Option<&'a Provenance>,
+    /// Part of the route to draw attention to
+    pub focus: Option<Focus>,
+    /// Passengers each service can carry, for load factors
+    pub capacity: Option<Quantity>,
+}
+
+/// The merged flows from one origin: (passengers, number of destinations)
+type Other = (Quantity, usize);
+
+fn split_small(
+    patronages: &BTreeMap<(StopId, StopId), Quantity>,
+    thresholds: Thresholds,
+) -> (BTreeMap<(StopId, StopId), Quantity>, BTreeMap<StopId, Other>) {
+    //! Separate the pairs to draw from those to merge, per `thresholds`.
+    //! Returns the pairs to draw and {`origin_stop` : merged flows}
+    let mut by_origin: BTreeMap<StopId, Vec<(StopId, Quantity)>> = BTreeMap::new();
+    for ((from, to), qty) in patronages {
+        by_origin.entry(*from).or_default().push((*to, *qty));
+    }
+
+    let mut shown = BTreeMap::new();
+    let mut others: BTreeMap<StopId, Other> = BTreeMap::new();
+    for (from, mut dests) in by_origin {
+        // biggest first; stable sort keeps ties in stop order
+        dests.sort_by_key(|d| std::cmp::Reverse(d.1));
+        for (rank, (to, qty)) in dests.into_iter().enumerate() {
+            if qty < thresholds.min_flow || thresholds.top_k.is_some_and(|k| rank >= k) {
+                let other = others.entry(from).or_default();
+                other.0 += qty;
+                other.1 += 1;
+            } else {
+                shown.insert((from, to), qty);
+            }
+        }
+    }
+    (shown, others)
+}
+
+pub fn segment_loads(
+    patronages: &BTreeMap<(StopId, StopId), Quantity>,
+    stop_sequence: &[StopId],
+) -> Vec<Quantity> {
+    //! The load after each stop in `stop_sequence`, i.e. between it and the next stop.
+    //! The last is the load continuing around the loop.
+
+    /* current_load calculations are a bit more complicated now that we go "through the loop".
+     * Current load is defined *between* stops, as the sum of the number of people in all
+     * arcs passing overhead. Luckily, since we have to evaluate wrap-arounds separately anyway
+     * we can just sum up all that patronage and know it will be accurate going in to the
+     * regular sequence... and then just subtract alightings and add boardings like normal.
+     */
+    let (boardings, alightings) = sum_up(patronages);
+    let seqi: BTreeMap<StopId, usize> =
+        stop_sequence.iter().enumerate().map(|(i, k)| (*k, i)).collect();
+
+    let mut current_load: Quantity = patronages
+        .iter()
+        .filter(
+            |((from, to), _)| matches!((seqi.get(from), seqi.get(to)), (Some(f), Some(t)) if f > t),
+        )
+        .map(|(_, qty)| qty)
+        .sum();
+    stop_sequence
+        .iter()
+        .map(|stop| {
+            current_load -= *alightings.get(stop).unwrap_or(&0);
+            current_load += *boardings.get(stop).unwrap_or(&0);
+            current_load
+        })
+        .collect()
+}
 
-        let mut out = Vec::with_capacity(len);
-        for i in 0..len {
-            out.push(input[(g * (i + g)) % len].clone());
+#[allow(clippy::too_many_arguments)]
+#[allow(clippy::non_ascii_literal)]
+pub fn make_title(
+    doc_width: f64,
+    route_name: &str,
+    direction: &str,
+    month: &str,
+    year: &str,
+    boards_count: Quantity,
+    service_count: Quantity,
+    ftime: Option<&str>,
+    trips: Option<&TripLengths>,
+) -> String {
+    //! The title and subtitle, centred at the top of the page
+    let ftime_ins = ftime.map_or_else(String::new, |s| format!("; {s}"));
+    // trip lengths get a line of their own, so the subtitle isn't too wide for short routes
+    let trips_ins = trips.map_or_else(String::new, |t| {
+        format!(
+            r#"
+    <text class="subtitle" x="{}" y="195">{:.0} passenger-km; mean trip {:.1} km, median {:.1} km</text>"#,
+            doc_width / 2.0,
+            t.passenger_km,
+            t.mean_km,
+            t.median_km
+        )
+    });
+
+    format!(
+        r#"<text class="title" x="{}" y="100">{} {} – {} {}</text>
+    <text class="subtitle" x="{}" y="150">{} boardings; est. {} services{}</text>{}"#, // {} services TODO
+        doc_width / 2.0,
+        escape(route_name),
+        escape(direction),
+        month,
+        year,
+        doc_width / 2.0,
+        boards_count,
+        service_count,
+        ftime_ins,
+        trips_ins
+    )
+}
+
+pub fn name_lines(name: &str, name_length: Option<usize>, wrap: bool) -> Vec<String> {
+    //! Fit a stop name to lines of at most `name_length` characters,
+    //! either by wrapping at spaces or by shortening it to one line.
+    //! Words too long for a line on their own are shortened regardless.
+    let Some(max) = name_length else {
+        return vec![String::from(name)];
+    };
+    let shorten = |s: &str| {
+        if s.chars().count() > max {
+            let mut short: String = s.chars().take(max.saturating_sub(1)).collect();
+            short.push('…');
+            short
+        } else {
+            String::from(s)
+        }
+    };
+    if !wrap {
+        return vec![shorten(name)];
+    }
+
+    let mut lines: Vec<String> = Vec::new();
+    for word in name.split_whitespace() {
+        match lines.last_mut() {
+            Some(line) if line.chars().count() + 1 + word.chars().count() <= max => {
+                line.push(' ');
+                line.push_str(word);
+            }
+            _ => lines.push(shorten(word)),
         }
-        out
     }
+    lines
 }
 
-fn sum_up(
+fn nice_number(x: f64) -> f64 {
+    //! The largest of 1, 2 or 5 times a power of ten that isn't more than `x` (minimum 1)
+    if x < 1.0 {
+        return 1.0;
+    }
+    let magnitude = 10f64.powf(x.log10().floor());
+    [5.0, 2.0, 1.0].iter().map(|m| m * magnitude).find(|n| *n <= x).unwrap_or(magnitude)
+}
+
+pub fn sum_up(
     patronages: &BTreeMap<(StopId, StopId), Quantity>,
 ) -> (BTreeMap<StopId, Quantity>, BTreeMap<StopId, Quantity>) {
     //! {(`origin_stop` : patronage} and {`destination_stop` : patronage}
@@ -104,36 +375,189 @@ fn sum_up(
     (boardings, alightings)
 }
 
-fn make_css(
-    swap_colours: bool,
-    jumble_colours: bool,
-    css_path: &Option<PathBuf>,
-    stop_count: usize,
+pub fn load_css(css_path: Option<&PathBuf>) -> Result<String> {
+    //! The user's CSS, or our default
+    Ok(match css_path {
+        Some(p) => std::fs::read_to_string(p)?,
+        None => String::from(include_str!("default.css")),
+    })
+}
+
+pub fn make_css(
+    colour_by: ColourBy,
+    arc_classes: Option<&ArcClasses>,
+    palette: &Palette,
+    css_path: Option<&PathBuf>,
+    stop_sequence: &[StopId],
 ) -> Result<String> {
     //! Construct CSS including its colour list.
+    //! Colour classes are keyed by `stop_id` (e.g. `.f1234`) rather than sequence index,
+    //! or are `.c0`, `.c1`... when colouring by some other attribute of each arc.
 
     // 1. load CSS
-    let mut css = match css_path.as_ref() {
-        Some(p) => std::fs::read_to_string(p)?,
-        None => String::from(include_str!("default.css")),
-    };
-    // 2. create colour list
-    let colours =
-        if jumble_colours { jumbled(colour_list(stop_count)) } else { colour_list(stop_count) };
-    // put colours into CSS
-    for (k, colour) in colours.iter().enumerate().take(stop_count) {
-        let colour_by = if swap_colours { "t" } else { "f" };
-        writeln!(css, ".{}{} {{stroke: {}}}", colour_by, k, colour)?;
+    let mut css = load_css(css_path)?;
+    // 2. create colour list and put colours into CSS
+    if let Some(ac) = arc_classes {
+        for (k, colour) in ac.colours.iter().enumerate() {
+            writeln!(css, ".c{k} {{stroke: {colour}}}")?;
+        }
+    } else {
+        let colours = palette.stop_colours(stop_sequence);
+        let prefix = if colour_by == ColourBy::Destination { "t" } else { "f" };
+        for (stop, colour) in stop_sequence.iter().zip(colours.iter()) {
+            writeln!(css, ".{prefix}{stop} {{stroke: {colour}}}")?;
+        }
     }
 
     Ok(css)
 }
 
+/// What arcs' colours mean, for the legend:
+/// what they're coloured by, then each colour's classes and what it stands for
+pub type ColourKey = (String, Vec<(String, String)>);
+
+pub fn colour_key(colour_by: ColourBy, arc_classes: &ArcClasses) -> ColourKey {
+    //! The key to colours of `arc_classes`
+    let swatches =
+        arc_classes.labels.iter().enumerate().map(|(k, label)| (format!("c{k}"), label.clone()));
+    (String::from(colour_by.describe()), swatches.collect())
+}
+
+pub fn make_legend(
+    key: Option<&ColourKey>,
+    tots_max: f64,
+    space: f64,
+    notes: &[String],
+    focus_note: Option<&str>,
+) -> Result<(String, f64)> {
+    //! A key to arc widths, and to the arc colours if they aren't simply by stop (`key`),
+    //! followed by any `notes` on how to read the diagram.
+    //! Any `focus_note` goes first. Also returns the height the legend takes up.
+    let mut legend = String::new();
+    writeln!(legend, r#"<g class="legend">"#)?;
+
+    // Starts below the title
+    let mut y = HEADER + UNIT / 2.0;
+    if let Some(note) = focus_note {
+        writeln!(
+            legend,
+            r#"<text class="legendhead focus" x="{MARGIN}" y="{y}">Focus: {note}</text>"#
+        )?;
+        y += 0.8 * UNIT;
+    }
+
+    // The scale: `tots_max` passengers fill `space` pixels
+    writeln!(
+        legend,
+        r#"<text class="legendhead" x="{MARGIN}" y="{y}">Scale: 1 px = {} passengers</text>"#,
+        format_sig(tots_max / space)
+    )?;
+    let sample = nice_number(tots_max / 2.0);
+    y += 0.8 * UNIT;
+    writeln!(
+        legend,
+        r#"<line class="scalebar swatch" x1="{MARGIN}" x2="{}" y1="{y}" y2="{y}" stroke-width="{:.5}" /><text class="legendtxt" x="{}" y="{y}">{sample} passengers</text>"#,
+        MARGIN + UNIT,
+        space * sample / tots_max,
+        1.3f64.mul_add(UNIT, MARGIN),
+    )?;
+
+    if let Some((what, swatches)) = key {
+        y += 1.2 * UNIT;
+        writeln!(legend, r#"<text class="legendhead" x="{MARGIN}" y="{y}">Colour: {what}</text>"#)?;
+        for (classes, label) in swatches {
+            y += 0.8 * UNIT;
+            writeln!(
+                legend,
+                r#"<line class="{classes} swatch" x1="{MARGIN}" x2="{}" y1="{y}" y2="{y}" stroke-width="{}" /><text class="legendtxt" x="{}" y="{y}">{}</text>"#,
+                MARGIN + UNIT,
+                0.4 * UNIT,
+                1.3f64.mul_add(UNIT, MARGIN),
+                escape(label),
+            )?;
+        }
+    }
+
+    if !notes.is_empty() {
+        y += 1.2 * UNIT;
+        writeln!(
+            legend,
+            r#"<text class="legendhead" x="{MARGIN}" y="{y}">How to read this</text>"#
+        )?;
+        for note in notes {
+            y += 0.6 * UNIT;
+            writeln!(legend, r#"<text class="legendtxt" x="{MARGIN}" y="{y}">{note}</text>"#)?;
+        }
+    }
+    writeln!(legend, "</g>")?;
+    Ok((legend, 0.5f64.mul_add(UNIT, y)))
+}
+
+fn make_notes(
+    colour_by: ColourBy,
+    thresholds: Thresholds,
+    merged: bool,
+    capacity: Option<Quantity>,
+    orientation: Orientation,
+) -> Vec<String> {
+    //! Explanations of each part of the diagram, for the legend,
+    //! worded for the way round the stops are laid out
+    let (start, end, bars) = match orientation {
+        Orientation::Landscape => ("left", "right", "bottom"),
+        Orientation::Portrait => ("top", "bottom", "left"),
+    };
+    let mut notes = vec![
+        format!("Each arc is a trip, from its origin on the {start} end to its destination on the {end} end."),
+        format!("Arcs leaving the {end} edge come back in at the {start}: they go around the loop."),
+        String::from("Arc widths are numbers of passengers (see the scale above)."),
+        format!("Arcs are coloured by {}.", colour_by.describe()),
+        format!("Bars along the {bars} show the load: passengers on board between each pair of stops."),
+        String::from("&#8634; is the load after the last stop, still on board around the loop."),
+        String::from("The highlighted bar is the peak load."),
+    ];
+    if let Some(c) = capacity {
+        notes.push(format!(
+            "Percentages are mean load factors: the load over the capacity of all services ({c} passengers each)."
+        ));
+    }
+    if merged {
+        let mut why = Vec::new();
+        if thresholds.min_flow > 0 {
+            why.push(format!("under {} passengers", thresholds.min_flow));
+        }
+        if let Some(k) = thresholds.top_k {
+            why.push(format!("outside the {k} largest from each stop"));
+        }
+        notes.push(format!(
+            "Grey stalks are flows {}, merged together at their origin.",
+            why.join(" or ")
+        ));
+    }
+    notes
+}
+
+pub fn escape(s: &str) -> String {
+    //! Escape text for SVG
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn format_sig(x: f64) -> String {
+    //! Format with about three significant figures
+    if x >= 100.0 {
+        format!("{x:.0}")
+    } else if x >= 10.0 {
+        format!("{x:.1}")
+    } else {
+        format!("{x:.2}")
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
 #[allow(clippy::too_many_lines)]
 /// Visualise a single route.
 /// Stops are laid out left-right in order of `stop_sequence`
 /// Arcs are drawn between stops according to `patronages`
+/// `distances` between consecutive stops, in metres, are needed for distance spacing
 /// etc, etc
 pub fn visualise_one(
     patronages: &BTreeMap<(StopId, StopId), Quantity>,
@@ -145,12 +569,27 @@ pub fn visualise_one(
     ftime: &Option<String>,
     month: &str,
     year: &str,
-    swap_colours: bool,
-    jumble_colours: bool,
-    css_path: &Option<PathBuf>,
+    options: &DrawOptions,
+    distances: Option<&[f64]>,
+    trips: Option<&TripLengths>,
 ) -> Result<String> {
+    let DrawOptions {
+        colour_by,
+        arc_classes,
+        palette,
+        css_path,
+        scale,
+        network_max,
+        thresholds,
+        layout,
+        provenance,
+        focus,
+        capacity,
+    } = *options;
     // we need to sum boardings and alightings for each stop_id so we know how wide to make arcs
+    // (and these are over all patronage, including any merged into "other")
     let (boardings, alightings) = sum_up(patronages);
+    let (shown, others) = split_small(patronages, thresholds);
 
     // and now we know what the ultimate sequence number of everything is...
     let mut seqi: BTreeMap<StopId, usize> = BTreeMap::new();
@@ -159,263 +598,373 @@ pub fn visualise_one(
     }
 
     let stop_count = stop_sequence.len();
-    let css = make_css(swap_colours, jumble_colours, css_path, stop_count)?;
+    let Layout { space, orientation, .. } = *layout;
+
+    // stop labels: the (perhaps shortened or wrapped) name, then a line of totals
+    let label_lines: Vec<Vec<String>> = stop_sequence
+        .iter()
+        .map(|stop| {
+            let name = stop_names.get(stop).cloned().unwrap_or_else(|| stop.to_string());
+            let mut lines = name_lines(&name, layout.name_length, layout.wrap_names);
+            lines.push(format!(
+                "{} alightings | {} boardings",
+                alightings.get(stop).unwrap_or(&0),
+                boardings.get(stop).unwrap_or(&0)
+            ));
+            lines
+        })
+        .collect();
+
+    // where each stop goes; with distance spacing, no closer than their labels need
+    let stops = Stops::new(layout, &label_lines, distances);
+
+    let css = make_css(colour_by, arc_classes, palette, css_path, stop_sequence)?;
+    // the focus, as sequence indexes; it's ignored if its stops aren't on this route
+    let focus_idx = focus.and_then(|f| match f {
+        Focus::Stop(stop) => Some((*seqi.get(&stop)?, None)),
+        Focus::Segment(a, b) => Some((*seqi.get(&a)?, Some(*seqi.get(&b)?))),
+    });
+    let in_focus = |from_idx: usize, to_idx: usize| match focus_idx {
+        None => true,
+        Some((stop, None)) => from_idx == stop || to_idx == stop,
+        // positions along this trip, which may go around the loop
+        Some((a, Some(b))) => {
+            let along = |i: usize| (i + stop_count - from_idx) % stop_count;
+            along(a) < along(b) && along(b) <= along(to_idx)
+        }
+    };
+
+    let arc_class = |from_idx: usize, to_idx: usize| {
+        let (from, to) = (stop_sequence[from_idx], stop_sequence[to_idx]);
+        let class = arc_classes
+            .and_then(|ac| ac.classes.get(&(from, to)))
+            .map_or_else(|| format!("f{from} t{to}"), |c| format!("f{from} t{to} c{c}"));
+        if in_focus(from_idx, to_idx) {
+            class
+        } else {
+            format!("{class} faded")
+        }
+    };
+    // for interactive output: which stops (by sequence index) an arc joins, and its passengers
+    let arc_data = |from_idx: usize, to_idx: usize, quantity: Quantity| {
+        format!(r#"data-from="{from_idx}" data-to="{to_idx}" data-qty="{quantity}""#)
+    };
 
     let mut paths_fwd = String::new();
     let mut paths_rev = String::new();
     let mut labels = String::new();
     let mut bargraph = String::new();
 
-    // hardcode worst-case for now, do something more intelligent later
-    let tier_max = stop_count as f64;
-
-    // other dimensions
-    let main_height = (tier_max + 2.0) * 0.5 * BETWEEN;
-    let main_width = (stop_count as f64 - 1.0) * BETWEEN;
-    let doc_width = EXTRA + main_width + EXTRA;
-    let doc_height = TEXT_SECTION + main_height;
-
-    let boarding_max = boardings.values().copied().max().unwrap_or(0);
-    let alighting_max = alightings.values().copied().max().unwrap_or(0);
-
-    let tots_max = f64::from(boarding_max + alighting_max) * SPACE / (BETWEEN - MIN_GAP);
-
-    let mut midline = format!(
-        r#"<line class="mainline" x1="{}" x2="{}" y1="{}" y2="{}" />"#,
-        EXTRA,
-        (stop_count as f64 - 1.0).mul_add(BETWEEN, EXTRA),
-        main_height,
-        main_height
-    );
-
-    /* current_load calculations are a bit more complicated now that we go "through the loop".
-     * Current load is defined *between* stops, as the sum of the number of people in all
-     * arcs passing overhead. Luckily, since we have to evaluate wrap-arounds separately anyway
-     * we can just sum up all that patronage and know it will be accurate going in to the
-     * regular sequence... and then just subtract alightings and add boardings like normal.
-     */
-
-    let mut current_load = 0;
-
-    /* Reverse arc layout has to be in a separate set of loops to forwards arc layout
-     * This is a little unfortunate due to the code duplication
-     * Reverse arcs need to be laid out in the following order (numbers are indexes):
-     * 1->0, 2->1, ... 2->0, 3->1, ... k-1 -> 0, where k is the number of stops
-     * But forward arcs want a different order: 0 -> 1, 0 -> 2, ... 1 -> 2, ... k-2 -> k-1.
-     * (Reverse arcs lay out tallest-first, forwards arcs lay out shortest-first).
-     */
-
-    let mut orig_subtotals: Vec<f64> = vec![0.0; stop_count];
-    let mut dest_subtotals: Vec<f64> = vec![0.0; stop_count];
-
-    for offset in 1..stop_count {
-        for to_idx in 0..(stop_count - offset) {
-            let from_idx = to_idx + offset;
-            let to = stop_sequence[to_idx];
-            let from = stop_sequence[from_idx];
-
-            let fromstr = from.to_string();
-            let from_name = stop_names.get(&from).unwrap_or(&fromstr);
-
-            let tostr = to.to_string();
-            let to_name = stop_names.get(&to).unwrap_or(&tostr);
-            let quantity = *patronages.get(&(from, to)).unwrap_or(&0);
-            if quantity < 1 {
-                continue;
-            }
-            // can just sum this all up for the wraparounds
-            current_load += quantity;
-
-            let alt_txt = format!("from: {}\nto: {}\npassengers: {}", from_name, to_name, quantity);
-
-            let y1 = main_height;
-            let y2 = y1;
-            let width = SPACE * f64::from(quantity) / tots_max;
-
-            // need to figure out arcs in/out of the page, and have two of them - "wrap around"
-            // these need to be two-arc paths!
-            let to_dest = dest_subtotals[to_idx];
-            let from_orig = orig_subtotals[from_idx];
-
-            let x1_right =
-                (from_idx as f64).mul_add(BETWEEN, EXTRA) + (from_orig + width / 2.0) + SPACE / 50.0;
-            let x2_left =
-                (to_idx as f64).mul_add(BETWEEN, EXTRA) - (width / 2.0 + to_dest + SPACE / 50.0);
-            let x2_right = (stop_count as f64).mul_add(BETWEEN, x2_left);
-            let x1_left = x1_right - (stop_count as f64 * BETWEEN);
+    // the arcs to draw, by sequence index
+    let shown_idx: BTreeMap<(usize, usize), Quantity> = shown
+        .iter()
+        .filter(|((from, to), qty)| **qty > 0 && from != to)
+        .filter_map(|((from, to), qty)| Some(((*seqi.get(from)?, *seqi.get(to)?), *qty)))
+        .collect();
+    let arc_height = stops.arc_height(shown_idx.keys().copied());
+
+    // the tightest squeeze: a stop's boardings and the next stop's alightings, in their gap
+    let count = |m: &BTreeMap<StopId, Quantity>| -> Vec<Quantity> {
+        stop_sequence.iter().map(|stop| *m.get(stop).unwrap_or(&0)).collect()
+    };
+    let squeeze = stops.squeeze(&count(&boardings), &count(&alightings));
 
-            let path = format!(
-                r#"<path class="arc f{} t{}" d="M{:.5} {} v{} A 1 1 0 1 1 {:.5} {} v{} M{:.5} {} v{} A1 1 0 1 1 {:.5} {} v{}" stroke-width="{:.5}"><title>{}</title></path>
-                "#,
-                from_idx,
-                to_idx,
-                x1_right,
-                doc_height,
-                -TEXT_SECTION,
-                x2_right,
-                y2,
-                TEXT_SECTION,
-                x1_left,
-                doc_height,
-                -TEXT_SECTION,
-                x2_left,
-                y2,
-                TEXT_SECTION,
-                width,
-                alt_txt
-            );
-            paths_rev.push_str(&path);
+    // tots_max passengers are drawn space pixels wide
+    let tots_max = match scale {
+        Scale::PerRoute => squeeze * space,
+        Scale::Global => f64::from(network_max) * space / (stops.between - stops.min_gap),
+        Scale::Fixed(n) => n * space,
+    };
 
-            orig_subtotals[from_idx] += width;
-            dest_subtotals[to_idx] += width;
+    // with provenance, we explain everything
+    let notes = match provenance {
+        Some(_) => make_notes(colour_by, thresholds, !others.is_empty(), capacity, orientation),
+        None => Vec::new(),
+    };
+    let focus_note = match focus_idx {
+        None => None,
+        Some((stop, None)) => {
+            let stop = stop_sequence[stop];
+            Some(format!(
+                "{}: {} boardings, {} alightings",
+                escape(&stop_names.get(&stop).cloned().unwrap_or_else(|| stop.to_string())),
+                boardings.get(&stop).unwrap_or(&0),
+                alightings.get(&stop).unwrap_or(&0)
+            ))
+        }
+        Some((a, Some(b))) => {
+            let name = |i: usize| {
+                let stop = stop_sequence[i];
+                escape(&stop_names.get(&stop).cloned().unwrap_or_else(|| stop.to_string()))
+            };
+            let on_board: Quantity = patronages
+                .iter()
+                .filter_map(|((from, to), qty)| {
+                    in_focus(*seqi.get(from)?, *seqi.get(to)?).then_some(qty)
+                })
+                .sum();
+            Some(format!("{} to {}: {on_board} passengers on board", name(a), name(b)))
         }
+    };
+    let key = arc_classes.map(|ac| colour_key(colour_by, ac));
+    let (legend, legend_height) =
+        make_legend(key.as_ref(), tots_max, space, &notes, focus_note.as_deref())?;
+
+    // (We do this up front, since the bargraph's height is needed to size the text section.)
+    let loads = segment_loads(patronages, stop_sequence);
+    let peak = peak_segment(&loads);
+    // passengers that every service put together could carry
+    let total_capacity =
+        capacity.map(|c| f64::from(c) * f64::from(service_count)).filter(|c| *c > 0.0);
+
+    let px = |quantity: Quantity| space * f64::from(quantity) / tots_max;
+    let tallest = px(loads.iter().copied().max().unwrap_or(0));
+    let widest = px(shown.values().copied().max().unwrap_or(0));
+    let geometry = stops.fit(layout, legend_height, arc_height, widest, &label_lines, tallest);
+    let Geometry { main_height, height: doc_height, .. } = geometry;
+
+    let mut midline = geometry.mainline();
+
+    let widths = shown_idx.iter().map(|(pair, qty)| (*pair, px(*qty))).collect();
+    let starts: Vec<f64> = count(&boardings).into_iter().map(px).collect();
+    let arcs = geometry.arcs(&widths, Some(&starts));
+
+    let name = |idx: usize| {
+        let stop = stop_sequence[idx];
+        stop_names.get(&stop).cloned().unwrap_or_else(|| stop.to_string())
+    };
+    let alt_txt = |arc: &Arc| {
+        format!(
+            "from: {}\nto: {}\npassengers: {}",
+            escape(&name(arc.from_idx)),
+            escape(&name(arc.to_idx)),
+            shown_idx[&(arc.from_idx, arc.to_idx)]
+        )
+    };
+    let path = |arc: &Arc| {
+        format!(
+            r#"<path class="arc {}" {} d="{}" stroke-width="{:.5}"><title>{}</title></path>
+        "#,
+            arc_class(arc.from_idx, arc.to_idx),
+            arc_data(arc.from_idx, arc.to_idx, shown_idx[&(arc.from_idx, arc.to_idx)]),
+            arc.d,
+            arc.width,
+            alt_txt(arc)
+        )
+    };
+    for arc in &arcs.wraparounds {
+        paths_rev.push_str(&path(arc));
     }
 
-    /* Now the "forward-arc" case
-     * Our visiting order here is of course 0 -> 1, 0 -> 2, ... 1 -> 2, ... k-2 -> k-1
-     */
+    let mut forwards = arcs.forwards.iter().peekable();
     for from_idx in 0..stop_count {
         let from = stop_sequence[from_idx];
-        let fromstr = from.to_string();
-        let from_name = stop_names.get(&from).unwrap_or(&fromstr);
-
-        let orig_total = SPACE * f64::from(*boardings.get(&from).unwrap_or(&0)) / tots_max;
-
-        // we're going outside-in here so the wraparound subtotals aren't relevant to us
-        // and due to how we iterate, we only need the scalar here
-        let mut orig_subtotal = 0.0;
-
-        for to_idx in (from_idx + 1)..stop_count {
-            let to = stop_sequence[to_idx];
-            let tostr = to.to_string();
-            let to_name = stop_names.get(&to).unwrap_or(&tostr);
-            let quantity = *patronages.get(&(from, to)).unwrap_or(&0);
-            if quantity == 0 {
-                continue;
-            }
-
-            let alt_txt = format!("from: {}\nto: {}\npassengers: {}", from_name, to_name, quantity);
-
-            // now we need to construct our path coordinates
-            let y1 = main_height;
-            let y2 = y1;
-            let width = SPACE * f64::from(quantity) / tots_max;
-
-            let dst = dest_subtotals[to_idx];
-
-            let x1 = (from_idx as f64).mul_add(BETWEEN, EXTRA)
-                + (orig_total - (orig_subtotal + width / 2.0))
-                + SPACE / 50.0;
-            let x2 = (to_idx as f64).mul_add(BETWEEN, EXTRA) - (width / 2.0 + dst + SPACE / 50.0);
-
+        let from_name = name(from_idx);
+
+        // merged flows sit between the wraparounds and the forward arcs, as a short stalk
+        if let Some((quantity, count)) = others.get(&from) {
+            // where these go is unknown, so they're only in focus at their own stop
+            let fade = match focus_idx {
+                None => "",
+                Some((stop, None)) if stop == from_idx => "",
+                Some(_) => " faded",
+            };
+            let width = px(*quantity);
+            let x =
+                geometry.stops.x[from_idx] + (arcs.wrapped[from_idx] + width / 2.0) + space / 50.0;
+            let alt_txt = format!(
+                "from: {}\nto: {count} other stops\npassengers: {quantity}",
+                escape(&from_name)
+            );
             let path = format!(
-                r#"<path class="arc f{} t{}" d="m{:.5} {} v{} A1 1 0 1 1 {:.5} {} v{}" stroke-width="{:.5}"><title>{}</title></path>
+                r#"<path class="arc other{fade}" data-from="{from_idx}" data-to="other" data-qty="{quantity}" d="M{x:.5} {doc_height} V{}" stroke-width="{width:.5}"><title>{alt_txt}</title></path>
         "#,
-                from_idx,
-                to_idx,
-                x1,
-                doc_height,
-                -TEXT_SECTION,
-                x2,
-                y2,
-                TEXT_SECTION,
-                width,
-                alt_txt
+                main_height - space,
             );
             paths_fwd.push_str(&path);
-            dest_subtotals[to_idx] += width;
-
-            orig_subtotal += width;
         }
-
-        let alights = *alightings.get(&from).unwrap_or(&0);
-        let boards = *boardings.get(&from).unwrap_or(&0);
+        while let Some(arc) = forwards.next_if(|arc| arc.from_idx == from_idx) {
+            paths_fwd.push_str(&path(arc));
+        }
 
         // label things
-        let line2 = format!("{} alightings | {} boardings", alights, boards);
-        let t_x = (from_idx as f64).mul_add(BETWEEN, EXTRA) - SPACE / 8.0;
-        let t_y = main_height + SPACE / 2.0;
-        let t_y2 = t_y + SPACE / 2.0;
-
-        for t_c in &["keyline", "foreground"] {
-            let label_txt = format!(
-                r#"<text class="{t_c}" font-size="25.0" text-anchor="end" transform="rotate(270,{t_x},{t_y})" x="{t_x}" y="{t_y}">{from_name}<tspan x="{t_x}" y="{t_y2}">{line2}</tspan></text>"#,
-                t_c = *t_c,
-                t_x = t_x,
-                t_y = t_y,
-                from_name = from_name,
-                t_y2 = t_y2,
-                line2 = line2
-            );
-            labels.push_str(&label_txt);
-        }
+        labels.push_str(&geometry.label(from_idx, &label_lines[from_idx])?);
 
         // bargraph things
-        current_load -= alights;
-        current_load += boards;
-
-        let b_x = (from_idx as f64).mul_add(BETWEEN, EXTRA) + BETWEEN / 2.0;
-        let b_y1 = doc_height;
-        let b_y2 = doc_height - ((SPACE * f64::from(current_load)) / tots_max);
-
-        let bar = format!(
-            r#"<line class="bargraph" stroke-width="{}" x1="{}" x2="{}" y1="{}" y2="{}" />"#,
-            BETWEEN, b_x, b_x, b_y1, b_y2
-        );
-        bargraph.push_str(&bar);
+        let current_load = loads[from_idx];
 
-        let loopy = if from_idx + 1 == stop_count {
-            // anticlockwise open circle arrow
-            "&#8634; "
-        } else {
-            ""
+        // the load between this stop and the next is part of a focused segment
+        let bar_focus = match focus_idx {
+            Some((a, Some(b))) => {
+                (from_idx + stop_count - a) % stop_count < (b + stop_count - a) % stop_count
+            }
+            _ => false,
         };
+        let classes = format!(
+            "{}{}",
+            if bar_focus { " focus" } else { "" },
+            if peak == Some(from_idx) { " peak" } else { "" },
+        );
+        bargraph.push_str(&geometry.bar(from_idx, px(current_load), &classes, ""));
 
-        for t_c in &["keyline", "foreground"] {
-            let bt = format!(
-                r#"<text class="{} bartxt" text-anchor="middle" x="{}" y="{}">{}{}</text>"#,
-                *t_c,
-                b_x,
-                b_y1 - SPACE / 5.0,
-                loopy,
-                current_load
-            );
-            bargraph.push_str(&bt);
-        }
+        let load_factor = total_capacity.map_or_else(String::new, |c| {
+            format!(" ({:.0}%)", 100.0 * f64::from(current_load) / c)
+        });
+        bargraph.push_str(&geometry.bar_text(from_idx, &format!("{current_load}{load_factor}"))?);
 
         // circle markers
-        let circ = format!(
-            r#"<circle class="markers" cx="{}" cy="{}" r="12.5" />"#,
-            (from_idx as f64).mul_add(BETWEEN, EXTRA),
-            main_height
+        let focus_class = match focus_idx {
+            Some((stop, None)) if stop == from_idx => " focus",
+            Some((a, Some(b))) if a == from_idx || b == from_idx => " focus",
+            _ => "",
+        };
+        let data = format!(
+            r#" data-name="{}" data-boardings="{}" data-alightings="{}""#,
+            escape(&from_name),
+            boardings.get(&from).unwrap_or(&0),
+            alightings.get(&from).unwrap_or(&0),
         );
-        midline.push_str(&circ);
+        midline.push_str(&geometry.marker(from_idx, focus_class, &data));
     }
 
-    let ftime_ins = match ftime.as_ref() {
-        Some(s) => format!("; {}", s),
-        None => String::new(),
-    };
+    let mut page = geometry.page();
+
+    // the footer goes under everything, whichever way round that is
+    let footer = provenance.map_or_else(String::new, |p| {
+        let filters = if p.filters.is_empty() { String::from("none") } else { p.filters.join(", ") };
+        let footer = format!(
+            r#"<text class="footer" x="{MARGIN}" y="{}">Patronage: {}; GTFS: {}; filters: {}; fluvial {}</text>"#,
+            page.height + UNIT,
+            escape(&p.patronage),
+            escape(&p.gtfs),
+            escape(&filters),
+            env!("CARGO_PKG_VERSION")
+        );
+        page.height += FOOTER;
+        footer
+    });
 
     let boards_count: Quantity = boardings.values().sum();
-    #[allow(clippy::non_ascii_literal)]
-    let title = format!(
-        r#"<text class="title" x="{}" y="100">{} {} – {} {}</text>
-    <text class="subtitle" x="{}" y="150">{} boardings; est. {} services{}</text>"#, // {} services TODO
-        doc_width / 2.0,
+    let title = make_title(
+        page.width,
         route_name,
         direction,
         month,
         year,
-        doc_width / 2.0,
         boards_count,
         service_count,
-        ftime_ins
+        ftime.as_deref(),
+        trips,
     );
 
-    Ok(format!(
-        // glorious hack: include_str! is eagerly evaluated
-        include_str!("template.svg"),
-        doc_width, doc_height, css, paths_rev, paths_fwd, labels, bargraph, midline, title
-    ))
+    let body = Body { paths_rev, paths_fwd, labels, bargraph, midline };
+    Ok(page.svg(&css, &body, &title, &legend, &footer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::colour::Scheme;
+    use crate::render::{render, Format};
+
+    #[test]
+    fn markup_in_stop_names_is_escaped() -> Result<()> {
+        let stop_sequence = vec![1, 2, 3];
+        let patronages = BTreeMap::from([((1, 2), 10), ((1, 3), 5), ((2, 3), 7), ((3, 1), 2)]);
+        let stop_names = BTreeMap::from([
+            (1, String::from("Smith & Sons")),
+            (2, String::from("<Depot>")),
+            (3, String::from("\"Quoted\" Stop")),
+        ]);
+        let palette = Palette {
+            scheme: Scheme::Hues,
+            start: 0.0,
+            stop: 360.0,
+            chroma: 90.0,
+            lightness: 55.0,
+            seed: 0,
+            jumble: false,
+            custom: None,
+        };
+        let layout = Layout {
+            space: 50.0,
+            between: 2.5,
+            spacing: Spacing::Uniform,
+            min_spacing: None,
+            min_gap: 0.5,
+            text_section: None,
+            edge: 2.0,
+            font_size: 25.0,
+            name_length: Some(10),
+            wrap_names: false,
+            orientation: Orientation::Landscape,
+            compact: None,
+        };
+        let svg = visualise_one(
+            &patronages,
+            &stop_sequence,
+            &stop_names,
+            4,
+            "R&D",
+            "Inbound",
+            &None,
+            "January",
+            "2020",
+            &DrawOptions {
+                colour_by: ColourBy::Origin,
+                arc_classes: None,
+                palette: &palette,
+                css_path: None,
+                scale: Scale::PerRoute,
+                network_max: 0,
+                thresholds: Thresholds { min_flow: 0, top_k: None },
+                layout: &layout,
+                provenance: None,
+                focus: None,
+                capacity: None,
+            },
+            None,
+            None,
+        )?;
+        assert!(svg.contains("Smith &amp; S…"));
+        assert!(!svg.contains("Smith & "));
+        render(&svg, Format::Png, 96.0, None)?;
+        Ok(())
+    }
+
+    #[test]
+    fn scales_parse() -> Result<()> {
+        assert_eq!("per-route".parse::<Scale>()?, Scale::PerRoute);
+        assert_eq!("global".parse::<Scale>()?, Scale::Global);
+        assert_eq!("fixed:2.5".parse::<Scale>()?, Scale::Fixed(2.5));
+        for bad in ["fixed:0", "fixed:-1", "fixed:", "fixed:lots", "fixed", "local"] {
+            assert!(bad.parse::<Scale>().is_err(), "{bad} should not parse");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn small_flows_are_merged_per_origin() {
+        let patronages =
+            BTreeMap::from([((1, 2), 10), ((1, 3), 2), ((1, 4), 3), ((2, 3), 1), ((2, 4), 8)]);
+        let (shown, others) = split_small(&patronages, Thresholds { min_flow: 3, top_k: None });
+        assert_eq!(shown, BTreeMap::from([((1, 2), 10), ((1, 4), 3), ((2, 4), 8)]));
+        assert_eq!(others, BTreeMap::from([(1, (2, 1)), (2, (1, 1))]));
+    }
+
+    #[test]
+    fn top_k_keeps_ties_in_stop_order() {
+        let patronages = BTreeMap::from([((1, 2), 5), ((1, 3), 9), ((1, 4), 5), ((1, 5), 5)]);
+        let (shown, others) = split_small(&patronages, Thresholds { min_flow: 0, top_k: Some(2) });
+        assert_eq!(shown, BTreeMap::from([((1, 2), 5), ((1, 3), 9)]));
+        assert_eq!(others, BTreeMap::from([(1, (10, 2))]));
+    }
+
+    #[test]
+    fn nothing_is_merged_by_default() {
+        let patronages = BTreeMap::from([((1, 2), 0), ((1, 3), 1)]);
+        let (shown, others) = split_small(&patronages, Thresholds { min_flow: 0, top_k: None });
+        assert_eq!(shown, patronages);
+        assert!(others.is_empty());
+    }
 }